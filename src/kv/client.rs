@@ -1,8 +1,11 @@
 use std::thread;
 use std::time::Duration;
 use super::common::*;
-use super::super::raft::rpc::Client;
+use super::super::raft::rpc::{Client, RpcError};
 use bincode::{serialize, deserialize};
+use serde::de::DeserializeOwned;
+
+const CALL_TIMEOUT: u64 = 6000;    // ms, a bit longer than servers wait for raft
 
 pub struct Clerk {
     servers: Vec<Client>,
@@ -12,81 +15,412 @@ pub struct Clerk {
 }
 
 impl Clerk {
-    pub fn new(servers: &Vec<Client>, client_id: u64) -> Clerk {
+    pub fn new(servers: &[Client], client_id: u64) -> Clerk {
         Clerk {
-            servers: servers.clone(),
+            servers: servers.to_vec(),
             client_id,
             request_seq: 0,
             leader_id: 0,
         }
     }
 
-    pub fn get(&mut self, key: &String) -> String {
+    // the value of key, "" if it does not exist.
+    pub fn get(&mut self, key: &str) -> String {
+        self.lookup(key).unwrap_or_default()
+    }
+
+    // the value of key, None if it does not exist. bytes which are not utf-8
+    // come back as U+FFFD, use get_bytes for binary values.
+    pub fn lookup(&mut self, key: &str) -> Option<String> {
+        self.get_bytes(key.as_bytes()).map(to_string)
+    }
+
+    pub fn put(&mut self, key: &str, value: &str) {
+        self.put_bytes(key.as_bytes(), value.as_bytes());
+    }
+
+    pub fn append(&mut self, key: &str, value: &str) {
+        self.append_bytes(key.as_bytes(), value.as_bytes());
+    }
+
+    // put key, which then reads as absent once ttl passed. the leader logging
+    // the put decides when that is, by its clock.
+    pub fn put_with_ttl(&mut self, key: &str, value: &str, ttl: Duration) {
+        self.put_with_ttl_bytes(key.as_bytes(), value.as_bytes(), ttl);
+    }
+
+    // remove key, nothing happens if it does not exist.
+    pub fn delete(&mut self, key: &str) {
+        self.delete_bytes(key.as_bytes());
+    }
+
+    // set key to new if it holds expected. returns whether it did and the
+    // value key holds afterwards.
+    pub fn compare_and_swap(&mut self, key: &str, expected: &str, new: &str) -> (bool, Option<String>) {
+        let (ok, value) = self.compare_and_swap_bytes(key.as_bytes(), expected.as_bytes(), new.as_bytes());
+        (ok, value.map(to_string))
+    }
+
+    // set key to value if it does not exist.
+    pub fn put_if_absent(&mut self, key: &str, value: &str) -> (bool, Option<String>) {
+        let (ok, value) = self.put_if_absent_bytes(key.as_bytes(), value.as_bytes());
+        (ok, value.map(to_string))
+    }
+
+    // remove key if it holds expected.
+    pub fn delete_if_equal(&mut self, key: &str, expected: &str) -> (bool, Option<String>) {
+        let (ok, value) = self.delete_if_equal_bytes(key.as_bytes(), expected.as_bytes());
+        (ok, value.map(to_string))
+    }
+
+    pub fn get_bytes(&mut self, key: &[u8]) -> Option<Vec<u8>> {
         let args = ReqArgs{
-            request_type: 0,
+            client_id: self.client_id,
             request_seq: self.request_seq,
-            cliend_id: self.client_id,
-            key: key.clone(),
-            value: String::new(),
-            op: String::new(),
+            command: Command::Get { key: key.to_vec() },
         };
         let req = serialize(&args).unwrap();
         loop {
 //            println!("--------send get rpc to {}", self.leader_id);
-            let (reply, success) = self.servers[self.leader_id as usize].call(
-                String::from("KV.Get"),
-                req.clone(),
-                );
-            if success {
-                let reply: GetReply = deserialize(&reply).unwrap();
-//                println!("--------receive get rpc response: {:?}", reply);
-                match reply.err {
-                    RespErr::OK => return reply.value,
-                    RespErr::ErrWrongLeader => (),
+            match self.call::<GetReply>("KV.Get", &req) {
+                Ok(reply) => {
+//                    println!("--------receive get rpc response: {:?}", reply);
+                    if reply.err == RespErr::OK {
+                        return reply.value;
+                    }
+                }
+                Err(err) => println!("KV.Get to server {} failed: {:?}", self.leader_id, err),
+            }
+            self.leader_id = (self.leader_id + 1) % (self.servers.len() as i32);
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    // the value of key with its version, None if it does not exist.
+    pub fn get_versioned(&mut self, key: &[u8]) -> Option<KeyValue> {
+        self.get_at(key, 0).unwrap()
+    }
+
+    // the value key had once the log entry at revision was applied, 0 for
+    // the latest. Err(ErrCompacted) if that was compacted away.
+    pub fn get_at(&mut self, key: &[u8], revision: usize) -> Result<Option<KeyValue>, RespErr> {
+        let req = serialize(&GetAtArgs { key: key.to_vec(), revision }).unwrap();
+        loop {
+            match self.call::<GetReply>("KV.GetAt", &req) {
+                Ok(reply) => match reply.err {
+                    RespErr::OK => {
+                        let GetReply { value, version, mod_revision, .. } = reply;
+                        return Ok(value.map(|value| KeyValue { value, version, mod_revision }));
+                    }
+                    RespErr::ErrCompacted | RespErr::ErrFutureRevision => return Err(reply.err),
+                    _ => (),
+                },
+                Err(err) => println!("KV.GetAt to server {} failed: {:?}", self.leader_id, err),
+            }
+            self.leader_id = (self.leader_id + 1) % (self.servers.len() as i32);
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    pub fn put_bytes(&mut self, key: &[u8], value: &[u8]) {
+        self.put_append(Command::Put { key: key.to_vec(), value: value.to_vec() });
+    }
+
+    pub fn append_bytes(&mut self, key: &[u8], value: &[u8]) {
+        self.put_append(Command::Append { key: key.to_vec(), value: value.to_vec() });
+    }
+
+    pub fn put_with_ttl_bytes(&mut self, key: &[u8], value: &[u8], ttl: Duration) {
+        self.put_append(Command::PutWithTtl { key: key.to_vec(), value: value.to_vec(), ttl: ttl.as_millis() as u64 });
+    }
+
+    pub fn delete_bytes(&mut self, key: &[u8]) {
+        self.put_append(Command::Delete { key: key.to_vec() });
+    }
+
+    // drop the versions reads at revision or later don't need. reads and
+    // watches before it fail with ErrCompacted from then on.
+    pub fn compact(&mut self, revision: usize) {
+        self.put_append(Command::Compact { revision });
+    }
+
+    fn put_append(&mut self, command: Command) {
+        let req = self.write_args(command);
+        loop {
+//            println!("--------send put rpc to {}", self.leader_id);
+            match self.call::<PutAppendReply>("KV.PutAppend", &req) {
+                Ok(reply) => {
+//                    println!("--------receive put rpc response, {:?}", reply);
+                    if reply.err == RespErr::OK {
+                        return;
+                    }
+                    assert_ne!(reply.err, RespErr::ErrBadCommand, "the servers can't decode our request");
+                }
+                Err(err) => println!("KV.PutAppend to server {} failed: {:?}", self.leader_id, err),
+            }
+            self.leader_id = (self.leader_id + 1) % (self.servers.len() as i32);
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    pub fn compare_and_swap_bytes(&mut self, key: &[u8], expected: &[u8], new: &[u8]) -> (bool, Option<Vec<u8>>) {
+        self.conditional(Command::CompareAndSwap { key: key.to_vec(), expected: expected.to_vec(), new: new.to_vec() })
+    }
+
+    pub fn put_if_absent_bytes(&mut self, key: &[u8], value: &[u8]) -> (bool, Option<Vec<u8>>) {
+        self.conditional(Command::PutIfAbsent { key: key.to_vec(), value: value.to_vec() })
+    }
+
+    pub fn delete_if_equal_bytes(&mut self, key: &[u8], expected: &[u8]) -> (bool, Option<Vec<u8>>) {
+        self.conditional(Command::DeleteIfEqual { key: key.to_vec(), expected: expected.to_vec() })
+    }
+
+    // retries carry the same seq, so a write applied before its reply got
+    // lost is not done twice and the retry gets the first outcome.
+    fn conditional(&mut self, command: Command) -> (bool, Option<Vec<u8>>) {
+        let req = self.write_args(command);
+        loop {
+            match self.call::<CasReply>("KV.Cas", &req) {
+                Ok(reply) => {
+                    if reply.err == RespErr::OK {
+                        return (reply.ok, reply.value);
+                    }
+                    assert_ne!(reply.err, RespErr::ErrBadCommand, "the servers can't decode our request");
+                }
+                Err(err) => println!("KV.Cas to server {} failed: {:?}", self.leader_id, err),
+            }
+            self.leader_id = (self.leader_id + 1) % (self.servers.len() as i32);
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    // a transaction on several keys, done as a single log entry:
+    //
+    //     clerk.txn().when(Guard::equals("a", "1")).then(TxnOp::put("b", "2")).or_else(TxnOp::delete("a")).commit()
+    pub fn txn(&mut self) -> Txn<'_> {
+        Txn { clerk: self, guards: Vec::new(), success: Vec::new(), failure: Vec::new() }
+    }
+
+    fn commit_txn(&mut self, command: Command) -> TxnOutcome {
+        let req = self.write_args(command);
+        loop {
+            match self.call::<TxnReply>("KV.Txn", &req) {
+                Ok(reply) => {
+                    if reply.err == RespErr::OK {
+                        return TxnOutcome { succeeded: reply.succeeded, guards: reply.guards };
+                    }
+                    assert_ne!(reply.err, RespErr::ErrBadCommand, "the servers can't decode our request");
                 }
+                Err(err) => println!("KV.Txn to server {} failed: {:?}", self.leader_id, err),
             }
             self.leader_id = (self.leader_id + 1) % (self.servers.len() as i32);
             thread::sleep(Duration::from_millis(100));
         }
     }
 
-    pub fn put(&mut self, key: &String, value: &String) {
-        let op = String::from("Put");
-        self.put_append(key, value, &op);
+    // the entries with start <= key < end in key order, limit at most per page.
+    // end None scans to the last key.
+    pub fn scan(&mut self, start: &[u8], end: Option<&[u8]>, limit: usize) -> ScanPage {
+        self.scan_at(start, end, limit, 0).unwrap()
+    }
+
+    // the entries whose key starts with prefix.
+    pub fn scan_prefix(&mut self, prefix: &[u8], limit: usize) -> ScanPage {
+        self.scan_at(prefix, prefix_end(prefix).as_deref(), limit, 0).unwrap()
     }
 
-    pub fn append(&mut self, key: &String, value: &String) {
-        let op = String::from("Append");
-        self.put_append(key, value, &op);
+    // the entries as of revision, 0 for the latest. all pages of a scan at a
+    // revision other than 0 together are a consistent snapshot of the range.
+    pub fn scan_at(&mut self, start: &[u8], end: Option<&[u8]>, limit: usize, revision: usize) -> Result<ScanPage, RespErr> {
+        let cursor = ScanCursor { start: start.to_vec(), end: end.map(|e| e.to_vec()), revision };
+        self.scan_next(&cursor, limit)
+    }
+
+    // the page after the one cursor came with. every page of a scan of the
+    // latest revision is a consistent read on its own, writes in between may
+    // show up in later pages. Err only for scans at a revision.
+    pub fn scan_next(&mut self, cursor: &ScanCursor, limit: usize) -> Result<ScanPage, RespErr> {
+        if cursor.end.as_ref().is_some_and(|end| end <= &cursor.start) {
+            return Ok(ScanPage { entries: Vec::new(), next: None, revision: cursor.revision });
+        }
+        let args = ScanArgs { start: cursor.start.clone(), end: cursor.end.clone(), limit, revision: cursor.revision };
+        let req = serialize(&args).unwrap();
+        loop {
+            match self.call::<ScanReply>("KV.Scan", &req) {
+                Ok(reply) => match reply.err {
+                    RespErr::OK => {
+                        let next = reply.next.map(|start| ScanCursor { start, end: cursor.end.clone(), revision: cursor.revision });
+                        return Ok(ScanPage { entries: reply.entries, next, revision: reply.revision });
+                    }
+                    RespErr::ErrCompacted | RespErr::ErrFutureRevision => return Err(reply.err),
+                    _ => (),
+                },
+                Err(err) => println!("KV.Scan to server {} failed: {:?}", self.leader_id, err),
+            }
+            self.leader_id = (self.leader_id + 1) % (self.servers.len() as i32);
+            thread::sleep(Duration::from_millis(100));
+        }
     }
 
-    fn put_append(&mut self, key: &String, value: &String, op: &String) {
+    // the changes to key applied at from_index or later, 0 for those after
+    // this call. a watcher which lost its server, or a new one from
+    // Watcher::next_index, picks up where the last left off.
+    pub fn watch(&mut self, key: &[u8], from_index: usize) -> Watcher {
+        self.start_watch(WatchArgs { key: key.to_vec(), prefix: false, from_index })
+    }
+
+    // the changes to every key starting with prefix.
+    pub fn watch_prefix(&mut self, prefix: &[u8], from_index: usize) -> Watcher {
+        self.start_watch(WatchArgs { key: prefix.to_vec(), prefix: true, from_index })
+    }
+
+    fn start_watch(&mut self, args: WatchArgs) -> Watcher {
+        let mut clerk = Clerk::new(&self.servers, self.client_id);
+        clerk.leader_id = self.leader_id;
+        let mut watcher = Watcher { clerk, args };
+        if watcher.args.from_index == 0 {
+            // pin down now, so changes made before the first recv are not missed
+            watcher.poll().unwrap();
+        }
+        watcher
+    }
+
+    // a new write request, encoded.
+    fn write_args(&mut self, command: Command) -> Vec<u8> {
         self.request_seq += 1;
         let args = ReqArgs {
-            request_type: 1,
+            client_id: self.client_id,
             request_seq: self.request_seq,
-            cliend_id: self.client_id,
-            key: key.clone(),
-            value: value.clone(),
-            op: op.clone(),
+            command,
         };
-        let req = serialize(&args).unwrap();
+        serialize(&args).unwrap()
+    }
+
+    // add server id listening on addr to the cluster.
+    // the server should have been started with KVServer::join.
+    pub fn add_server(&mut self, id: i32, addr: &str) -> Result<(), RespErr> {
+        let args = ConfigChangeArgs::AddServer { id, addr: addr.to_string() };
+        self.change_config(&args)?;
+        if !self.servers.iter().any(|s| s.server_addr == addr) {
+            self.servers.push(Client{end_name: String::from(""), server_addr: addr.to_string()});
+        }
+        Ok(())
+    }
+
+    pub fn remove_server(&mut self, id: i32) -> Result<(), RespErr> {
+        self.change_config(&ConfigChangeArgs::RemoveServer { id })
+    }
+
+    fn change_config(&mut self, args: &ConfigChangeArgs) -> Result<(), RespErr> {
+        let req = serialize(args).unwrap();
         loop {
-//            println!("--------send put rpc to {}", self.leader_id);
-            let (reply, success) = self.servers[self.leader_id as usize].call(
-                String::from("KV.PutAppend"),
-                req.clone(),
-                );
-            if success {
-                let reply: PutAppendReply = deserialize(&reply).unwrap();
-//                println!("--------receive put rpc response, {:?}", reply);
-                match reply.err {
-                    RespErr::OK => return,
-                    RespErr::ErrWrongLeader => (),
-                }
+            match self.call::<ConfigChangeReply>("KV.ChangeConfig", &req) {
+                Ok(reply) => match reply.err {
+                    RespErr::OK => return Ok(()),
+                    RespErr::ErrConfigInProgress => {
+                        // same leader, wait for the running change to finish
+                        thread::sleep(Duration::from_millis(100));
+                        continue;
+                    }
+                    RespErr::ErrConfigRejected | RespErr::ErrBadCommand => return Err(reply.err),
+                    RespErr::ErrWrongLeader | RespErr::ErrCompacted | RespErr::ErrFutureRevision => (),
+                },
+                Err(err) => println!("KV.ChangeConfig to server {} failed: {:?}", self.leader_id, err),
             }
             self.leader_id = (self.leader_id + 1) % (self.servers.len() as i32);
             thread::sleep(Duration::from_millis(100));
         }
     }
+
+    // call the server we take for the leader. any error just makes us try the
+    // next server: it may be down, still starting up, or partitioned away.
+    fn call<R: DeserializeOwned>(&self, svc_meth: &str, req: &[u8]) -> Result<R, RpcError> {
+        let reply = self.servers[self.leader_id as usize].call_with_timeout(
+            svc_meth.to_string(),
+            req.to_vec(),
+            Duration::from_millis(CALL_TIMEOUT),
+            )?;
+        deserialize(&reply).map_err(|_| RpcError::Decode)
+    }
+}
+
+// a transaction being built by Clerk::txn. if every guard holds the then ops
+// are done in order, otherwise the or_else ops are. no guards always holds.
+pub struct Txn<'a> {
+    clerk: &'a mut Clerk,
+    guards: Vec<Guard>,
+    success: Vec<TxnOp>,
+    failure: Vec<TxnOp>,
+}
+
+impl<'a> Txn<'a> {
+    pub fn when(mut self, guard: Guard) -> Txn<'a> {
+        self.guards.push(guard);
+        self
+    }
+
+    pub fn then(mut self, op: TxnOp) -> Txn<'a> {
+        self.success.push(op);
+        self
+    }
+
+    pub fn or_else(mut self, op: TxnOp) -> Txn<'a> {
+        self.failure.push(op);
+        self
+    }
+
+    // retried like conditional writes, a retry gets the first outcome.
+    pub fn commit(self) -> TxnOutcome {
+        let Txn { clerk, guards, success, failure } = self;
+        clerk.commit_txn(Command::Txn { guards, success, failure })
+    }
+}
+
+// changes to the keys a watch is on, see Clerk::watch.
+pub struct Watcher {
+    clerk: Clerk,
+    args: WatchArgs,
+}
+
+impl Watcher {
+    // the next changes in the order they were applied, waiting until there
+    // are some. Err(ErrCompacted) if they were compacted away, read the keys
+    // and watch again from now on.
+    pub fn recv(&mut self) -> Result<Vec<WatchEvent>, RespErr> {
+        loop {
+            let events = self.poll()?;
+            if !events.is_empty() {
+                return Ok(events);
+            }
+        }
+    }
+
+    // the index to watch from for the changes after those received.
+    pub fn next_index(&self) -> usize {
+        self.args.from_index
+    }
+
+    fn poll(&mut self) -> Result<Vec<WatchEvent>, RespErr> {
+        let req = serialize(&self.args).unwrap();
+        let clerk = &mut self.clerk;
+        loop {
+            match clerk.call::<WatchReply>("KV.Watch", &req) {
+                Ok(reply) => match reply.err {
+                    RespErr::OK => {
+                        self.args.from_index = reply.next;
+                        return Ok(reply.events);
+                    }
+                    RespErr::ErrCompacted => return Err(reply.err),
+                    _ => (),
+                },
+                Err(err) => println!("KV.Watch to server {} failed: {:?}", clerk.leader_id, err),
+            }
+            clerk.leader_id = (clerk.leader_id + 1) % (clerk.servers.len() as i32);
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+fn to_string(value: Vec<u8>) -> String {
+    String::from_utf8_lossy(&value).into_owned()
 }
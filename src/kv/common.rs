@@ -1,17 +1,194 @@
+use bincode::{serialize, deserialize, serialized_size};
+
 #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub enum RespErr {
     OK,
     ErrWrongLeader,
+    ErrConfigInProgress,    // another membership change is still running, retry later
+    ErrConfigRejected,      // the change would leave the cluster without servers
+    ErrCompacted,           // the changes or versions asked for were compacted away
+    ErrFutureRevision,      // the revision asked for has not been applied yet
+    ErrBadCommand,          // the servers can't decode the command, retrying won't help
+}
+
+// log entries start with the version of their encoding. entries from before
+// there were versions are a bare LegacyArgs, which starts with its
+// request_type, 0 or 1, so versions start at 2.
+const COMMAND_V2: u8 = 2;
+const COMMAND_V3: u8 = 3;  // also carries the clock of the leader which logged it
+
+// what a clerk asks of the state machine. keys and values are arbitrary bytes.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub enum Command {
+    Get { key: Vec<u8> },
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Append { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+    CompareAndSwap { key: Vec<u8>, expected: Vec<u8>, new: Vec<u8> },
+    PutIfAbsent { key: Vec<u8>, value: Vec<u8> },
+    DeleteIfEqual { key: Vec<u8>, expected: Vec<u8> },
+    PutWithTtl { key: Vec<u8>, value: Vec<u8>, ttl: u64 },  // ttl in ms from when the leader logged it
+    Sweep,      // leaders log these to drop the keys which expired by then
+    Txn { guards: Vec<Guard>, success: Vec<TxnOp>, failure: Vec<TxnOp> },  // success if every guard holds, else failure
+    Compact { revision: usize },  // drop the versions no read at revision or later needs
+}
+
+impl Command {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Command::Get { key } |
+            Command::Put { key, .. } |
+            Command::Append { key, .. } |
+            Command::Delete { key } |
+            Command::CompareAndSwap { key, .. } |
+            Command::PutIfAbsent { key, .. } |
+            Command::DeleteIfEqual { key, .. } |
+            Command::PutWithTtl { key, .. } => key,
+            Command::Sweep | Command::Txn { .. } | Command::Compact { .. } => &[],
+        }
+    }
+}
+
+// a condition on one key of a transaction.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub enum Guard {
+    Equals { key: Vec<u8>, value: Vec<u8> },
+    Exists { key: Vec<u8> },
+    Absent { key: Vec<u8> },
+}
+
+impl Guard {
+    pub fn equals(key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Guard {
+        Guard::Equals { key: key.as_ref().to_vec(), value: value.as_ref().to_vec() }
+    }
+
+    pub fn exists(key: impl AsRef<[u8]>) -> Guard {
+        Guard::Exists { key: key.as_ref().to_vec() }
+    }
+
+    pub fn absent(key: impl AsRef<[u8]>) -> Guard {
+        Guard::Absent { key: key.as_ref().to_vec() }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Guard::Equals { key, .. } | Guard::Exists { key } | Guard::Absent { key } => key,
+        }
+    }
+}
+
+// a write done by a transaction.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub enum TxnOp {
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Append { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+impl TxnOp {
+    pub fn put(key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> TxnOp {
+        TxnOp::Put { key: key.as_ref().to_vec(), value: value.as_ref().to_vec() }
+    }
+
+    pub fn append(key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> TxnOp {
+        TxnOp::Append { key: key.as_ref().to_vec(), value: value.as_ref().to_vec() }
+    }
+
+    pub fn delete(key: impl AsRef<[u8]>) -> TxnOp {
+        TxnOp::Delete { key: key.as_ref().to_vec() }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        match self {
+            TxnOp::Put { key, .. } | TxnOp::Append { key, .. } | TxnOp::Delete { key } => key,
+        }
+    }
+}
+
+impl From<TxnOp> for Command {
+    fn from(op: TxnOp) -> Command {
+        match op {
+            TxnOp::Put { key, value } => Command::Put { key, value },
+            TxnOp::Append { key, value } => Command::Append { key, value },
+            TxnOp::Delete { key } => Command::Delete { key },
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub struct ReqArgs {
-    pub request_type: u8,
-    pub cliend_id: u64,
+    pub client_id: u64,
     pub request_seq: u64,
-    pub key: String,
-    pub value: String,
-    pub op: String,
+    pub command: Command,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum DecodeError {
+    UnknownVersion(u8),
+    UnknownOp(String),
+    Malformed,
+}
+
+impl ReqArgs {
+    // the raft log entry for the request, now is the leader's clock in ms
+    // since the unix epoch.
+    pub fn encode(&self, now: u64) -> Vec<u8> {
+        let mut buf = vec![COMMAND_V3];
+        buf.extend(serialize(&(now, self)).unwrap());
+        buf
+    }
+
+    // the request and the clock of the leader which logged it, 0 for entries
+    // from before leaders logged their clock.
+    pub fn decode(buf: &[u8]) -> Result<(ReqArgs, u64), DecodeError> {
+        match buf.first() {
+            Some(&COMMAND_V3) => {
+                let (now, args) = deserialize(&buf[1..]).map_err(|_| DecodeError::Malformed)?;
+                Ok((args, now))
+            },
+            Some(&COMMAND_V2) => deserialize(&buf[1..]).map(|args| (args, 0)).map_err(|_| DecodeError::Malformed),
+            Some(0) | Some(1) => LegacyArgs::decode(buf).map(|args| (args, 0)),
+            Some(&version) => Err(DecodeError::UnknownVersion(version)),
+            None => Err(DecodeError::Malformed),
+        }
+    }
+}
+
+// how requests were logged before commands were typed. keys and values were
+// strings, which bincode lays out just like bytes.
+#[derive(Serialize, Deserialize)]
+struct LegacyArgs {
+    request_type: u8,   // 0 for Get, 1 for a write named by op
+    cliend_id: u64,
+    request_seq: u64,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    op: String,
+}
+
+impl LegacyArgs {
+    // conditional writes appended what they expected after the other fields.
+    fn decode(buf: &[u8]) -> Result<ReqArgs, DecodeError> {
+        let old: LegacyArgs = deserialize(buf).map_err(|_| DecodeError::Malformed)?;
+        let rest = &buf[serialized_size(&old).unwrap() as usize..];
+        let expected = if rest.is_empty() {
+            Vec::new()
+        } else {
+            deserialize(rest).map_err(|_| DecodeError::Malformed)?
+        };
+        let LegacyArgs { request_type, cliend_id, request_seq, key, value, op } = old;
+        let command = match (request_type, op.as_str()) {
+            (0, _) => Command::Get { key },
+            (_, "Put") => Command::Put { key, value },
+            (_, "Append") => Command::Append { key, value },
+            (_, "Delete") => Command::Delete { key },
+            (_, "CompareAndSwap") => Command::CompareAndSwap { key, expected, new: value },
+            (_, "PutIfAbsent") => Command::PutIfAbsent { key, value },
+            (_, "DeleteIfEqual") => Command::DeleteIfEqual { key, expected },
+            _ => return Err(DecodeError::UnknownOp(op)),
+        };
+        Ok(ReqArgs { client_id: cliend_id, request_seq, command })
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
@@ -19,8 +196,181 @@ pub struct PutAppendReply {
     pub err: RespErr,
 }
 
+// the key as of revision, the index of a log entry. 0 for the latest.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct GetAtArgs {
+    pub key: Vec<u8>,
+    pub revision: usize,
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub struct GetReply {
     pub err: RespErr,
-    pub value: String,
+    pub value: Option<Vec<u8>>, // None if the key does not exist
+    pub version: u64,           // writes to the key since it was last created, 0 if it does not exist
+    pub mod_revision: usize,    // revision of the last of those writes
+}
+
+// a value together with where it came from, see GetReply.
+#[derive(PartialEq, Clone, Debug)]
+pub struct KeyValue {
+    pub value: Vec<u8>,
+    pub version: u64,
+    pub mod_revision: usize,
+}
+
+// outcome of a conditional write.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct CasReply {
+    pub err: RespErr,
+    pub ok: bool,               // the condition held and the write was done
+    pub value: Option<Vec<u8>>, // the key's value after the request
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct TxnReply {
+    pub err: RespErr,
+    pub succeeded: bool,        // every guard held and the success ops were done
+    pub guards: Vec<bool>,      // whether each guard held, in the order they were given
+}
+
+// what a transaction did, see TxnReply.
+#[derive(PartialEq, Clone, Debug)]
+pub struct TxnOutcome {
+    pub succeeded: bool,
+    pub guards: Vec<bool>,
+}
+
+// keys from start up to but not including end, or to the last key if end is None.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct ScanArgs {
+    pub start: Vec<u8>,
+    pub end: Option<Vec<u8>>,
+    pub limit: usize,           // at most this many entries, 0 for as many as the server allows
+    pub revision: usize,        // read the keys as of this revision, 0 for the latest
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct ScanReply {
+    pub err: RespErr,
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+    pub next: Option<Vec<u8>>,  // first key of the next page, None if this was the last
+    pub revision: usize,        // the revision the page was read at
+}
+
+// one page of a scan. next picks up where it ended, None once the range is done.
+// scanning again at revision sees the keys just as this page did.
+#[derive(PartialEq, Clone, Debug)]
+pub struct ScanPage {
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+    pub next: Option<ScanCursor>,
+    pub revision: usize,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct ScanCursor {
+    pub(crate) start: Vec<u8>,
+    pub(crate) end: Option<Vec<u8>>,
+    pub(crate) revision: usize,
+}
+
+// the first key after every key starting with prefix, None if there is none.
+pub fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+// changes to key, or to every key starting with it if prefix, applied at
+// from_index or later. from_index 0 asks where the log is up to.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct WatchArgs {
+    pub key: Vec<u8>,
+    pub prefix: bool,
+    pub from_index: usize,
+}
+
+impl WatchArgs {
+    pub fn matches(&self, key: &[u8]) -> bool {
+        if self.prefix { key.starts_with(&self.key) } else { key == self.key.as_slice() }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct WatchReply {
+    pub err: RespErr,
+    pub events: Vec<WatchEvent>,
+    pub next: usize,            // index to watch from for the changes after these
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+pub enum EventOp {
+    Put,        // any write which left the key with a value
+    Delete,
+    Expire,     // its ttl ran out
+}
+
+// key changed at the log entry index, value is what it holds afterwards.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct WatchEvent {
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>,
+    pub op: EventOp,
+    pub index: usize,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub enum ConfigChangeArgs {
+    AddServer { id: i32, addr: String },
+    RemoveServer { id: i32 },
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct ConfigChangeReply {
+    pub err: RespErr,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_encoding_test() {
+        let args = ReqArgs {
+            client_id: 7,
+            request_seq: 3,
+            command: Command::CompareAndSwap { key: b"k".to_vec(), expected: b"a".to_vec(), new: b"b".to_vec() },
+        };
+        assert_eq!(ReqArgs::decode(&args.encode(42)), Ok((args.clone(), 42)));
+        let mut buf = vec![COMMAND_V2];
+        buf.extend(serialize(&args).unwrap());
+        assert_eq!(ReqArgs::decode(&buf), Ok((args, 0)));
+
+        // entries logged before commands were typed
+        let old = LegacyArgs {
+            request_type: 1,
+            cliend_id: 7,
+            request_seq: 3,
+            key: b"k".to_vec(),
+            value: b"v".to_vec(),
+            op: "Append".to_string(),
+        };
+        let append = ReqArgs { client_id: 7, request_seq: 3, command: Command::Append { key: b"k".to_vec(), value: b"v".to_vec() } };
+        assert_eq!(ReqArgs::decode(&serialize(&old).unwrap()), Ok((append, 0)));
+
+        let mut buf = serialize(&LegacyArgs { op: "DeleteIfEqual".to_string(), ..old }).unwrap();
+        buf.extend(serialize("v").unwrap());
+        let delete = ReqArgs { client_id: 7, request_seq: 3, command: Command::DeleteIfEqual { key: b"k".to_vec(), expected: b"v".to_vec() } };
+        assert_eq!(ReqArgs::decode(&buf), Ok((delete, 0)));
+
+        // which used to be applied as an Append
+        let buf = serialize(&(1u8, 7u64, 3u64, "k", "v", "Incr")).unwrap();
+        assert_eq!(ReqArgs::decode(&buf), Err(DecodeError::UnknownOp("Incr".to_string())));
+        assert_eq!(ReqArgs::decode(&[9]), Err(DecodeError::UnknownVersion(9)));
+    }
 }
@@ -0,0 +1,241 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Instant;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Input {
+    Get,
+    Put(String),
+    Append(String),
+    Delete,
+}
+
+// one finished clerk call. times are ns since the history started.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Operation {
+    pub client: u64,
+    pub key: String,
+    pub input: Input,
+    pub output: String,     // what a Get returned, empty for writes
+    pub call: u64,
+    pub ret: u64,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{:>8}us, {:>8}us] client {}: ", self.call / 1000, self.ret / 1000, self.client)?;
+        match self.input {
+            Input::Get => write!(f, "Get({:?}) -> {:?}", self.key, self.output),
+            Input::Put(ref v) => write!(f, "Put({:?}, {:?})", self.key, v),
+            Input::Append(ref v) => write!(f, "Append({:?}, {:?})", self.key, v),
+            Input::Delete => write!(f, "Delete({:?})", self.key),
+        }
+    }
+}
+
+// operations of concurrent clerks, in the order they returned.
+pub struct History {
+    start: Instant,
+    ops: Mutex<Vec<Operation>>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            start: Instant::now(),
+            ops: Mutex::new(Vec::new()),
+        }
+    }
+
+    // run f, the clerk call for input on key, and record when it was invoked
+    // and when it returned. f returns what a Get read.
+    pub fn record<F: FnOnce() -> String>(&self, client: u64, key: &str, input: Input, f: F) -> String {
+        let call = self.start.elapsed().as_nanos() as u64;
+        let output = f();
+        let ret = self.start.elapsed().as_nanos() as u64;
+        let op = Operation { client, key: key.to_string(), input, output: output.clone(), call, ret };
+        self.ops.lock().unwrap().push(op);
+        output
+    }
+
+    pub fn operations(&self) -> Vec<Operation> {
+        self.ops.lock().unwrap().clone()
+    }
+}
+
+// check ops against a sequential kv store where missing keys read as "".
+// keys don't affect each other so each one is checked on its own. if a key's
+// operations can't be linearized, return a smallest subset of them which
+// still can't, sorted by invocation.
+pub fn check(ops: &[Operation]) -> Result<(), Vec<Operation>> {
+    let mut by_key: BTreeMap<&str, Vec<Operation>> = BTreeMap::new();
+    for op in ops {
+        by_key.entry(&op.key).or_default().push(op.clone());
+    }
+    for (_, ops) in by_key {
+        if !linearizable(&ops) {
+            return Err(minimize(ops));
+        }
+    }
+    Ok(())
+}
+
+// the state after op, None if op could not have happened in state.
+fn apply(state: &str, op: &Operation) -> Option<String> {
+    match op.input {
+        Input::Get if op.output == state => Some(state.to_string()),
+        Input::Get => None,
+        Input::Put(ref v) => Some(v.clone()),
+        Input::Append(ref v) => Some(format!("{}{}", state, v)),
+        Input::Delete => Some(String::new()),
+    }
+}
+
+// the Wing & Gong search with Lowe's memoization, as Porcupine does it. walk
+// the calls and returns in time order: a call is linearized right away if the
+// model allows it and that (set of linearized ops, state) was not tried
+// before, a return whose op was not linearized yet means an earlier choice was
+// wrong, so undo the last one and try the next call after it.
+fn linearizable(ops: &[Operation]) -> bool {
+    let n = ops.len();
+    // (time, is return, op), calls first on a tie so such ops count as concurrent
+    let mut events: Vec<(u64, bool, usize)> = Vec::with_capacity(2 * n);
+    for (i, op) in ops.iter().enumerate() {
+        events.push((op.call, false, i));
+        events.push((op.ret, true, i));
+    }
+    events.sort();
+
+    // a doubly linked list of the events not linearized yet
+    let (head, tail) = (events.len(), events.len() + 1);
+    let mut next = vec![tail; events.len() + 2];
+    let mut prev = vec![head; events.len() + 2];
+    let mut call_at = vec![0; n];
+    let mut ret_at = vec![0; n];
+    let mut last = head;
+    for (e, &(_, is_ret, i)) in events.iter().enumerate() {
+        if is_ret { ret_at[i] = e } else { call_at[i] = e }
+        next[last] = e;
+        prev[e] = last;
+        last = e;
+    }
+    next[last] = tail;
+    prev[tail] = last;
+
+    let unlink = |next: &mut Vec<usize>, prev: &mut Vec<usize>, e: usize| {
+        next[prev[e]] = next[e];
+        prev[next[e]] = prev[e];
+    };
+    let relink = |next: &mut Vec<usize>, prev: &mut Vec<usize>, e: usize| {
+        next[prev[e]] = e;
+        prev[next[e]] = e;
+    };
+
+    let mut linearized = vec![0u64; n / 64 + 1];
+    let mut seen: HashSet<(Vec<u64>, String)> = HashSet::new();
+    let mut stack: Vec<(usize, String)> = Vec::new();   // linearized ops and the state before each
+    let mut state = String::new();
+    let mut e = next[head];
+    while next[head] != tail {
+        let (_, is_ret, i) = events[e];
+        if !is_ret {
+            if let Some(new_state) = apply(&state, &ops[i]) {
+                linearized[i / 64] |= 1 << (i % 64);
+                if seen.insert((linearized.clone(), new_state.clone())) {
+                    stack.push((i, std::mem::replace(&mut state, new_state)));
+                    unlink(&mut next, &mut prev, call_at[i]);
+                    unlink(&mut next, &mut prev, ret_at[i]);
+                    e = next[head];
+                    continue;
+                }
+                linearized[i / 64] &= !(1 << (i % 64));
+            }
+            e = next[e];
+        } else {
+            let (i, old_state) = match stack.pop() {
+                Some(top) => top,
+                None => return false,
+            };
+            linearized[i / 64] &= !(1 << (i % 64));
+            state = old_state;
+            relink(&mut next, &mut prev, ret_at[i]);
+            relink(&mut next, &mut prev, call_at[i]);
+            e = next[call_at[i]];
+        }
+    }
+    true
+}
+
+// drop operations one at a time as long as the rest still can't be linearized.
+// writes some remaining Get saw stay, without them the report would be a bare
+// read of a value nobody wrote rather than the read and the writes it missed.
+fn minimize(mut ops: Vec<Operation>) -> Vec<Operation> {
+    ops.sort_by_key(|op| op.call);
+    let mut i = 0;
+    while i < ops.len() {
+        let mut rest = ops.clone();
+        rest.remove(i);
+        let seen = match ops[i].input {
+            Input::Get | Input::Delete => false,
+            Input::Put(ref v) | Input::Append(ref v) => {
+                rest.iter().any(|op| op.input == Input::Get && op.output.contains(v.as_str()))
+            },
+        };
+        if seen || linearizable(&rest) {
+            i += 1;
+        } else {
+            ops = rest;
+        }
+    }
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn op(client: u64, input: Input, output: &str, call: u64, ret: u64) -> Operation {
+        Operation { client, key: String::from("k"), input, output: output.to_string(), call, ret }
+    }
+
+    #[test]
+    fn linearizability_check_test() {
+        let put = |v: &str| Input::Put(v.to_string());
+        let append = |v: &str| Input::Append(v.to_string());
+
+        // concurrent writes may take effect in either order
+        let ops = vec![
+            op(0, put("1"), "", 0, 10),
+            op(1, put("2"), "", 5, 15),
+            op(2, Input::Get, "1", 20, 30),
+            op(3, append("x"), "", 25, 40),
+            op(2, Input::Get, "1x", 35, 50),
+        ];
+        assert_eq!(check(&ops), Ok(()));
+
+        // reads overlapping a write may see it or not, but never unsee it
+        let mut ops = vec![
+            op(0, put("1"), "", 0, 10),
+            op(1, append("2"), "", 20, 40),
+            op(2, Input::Get, "12", 25, 30),
+            op(3, Input::Get, "1", 26, 35),
+        ];
+        assert_eq!(check(&ops), Ok(()));
+        ops[3].call = 31;
+        assert!(check(&ops).is_err());
+
+        // a stale read, reported with the writes that explain it
+        let mut ops = vec![
+            op(0, put("1"), "", 0, 10),
+            op(1, put("2"), "", 20, 30),
+            op(2, Input::Get, "2", 35, 40),
+            op(3, Input::Get, "1", 45, 50),
+        ];
+        for i in 0..5 {
+            ops.push(Operation { key: format!("other{}", i), ..op(4, put("x"), "", 60, 70) });
+        }
+        let bad = check(&ops).unwrap_err();
+        assert_eq!(bad, vec![op(0, put("1"), "", 0, 10), op(1, put("2"), "", 20, 30), op(3, Input::Get, "1", 45, 50)]);
+    }
+}
@@ -1,6 +1,8 @@
 pub mod client;
 pub mod server;
 pub mod common;
+#[cfg(test)]
+mod linearizability;
 
 #[cfg(test)]
 mod tests {
@@ -8,40 +10,59 @@ mod tests {
     use super::server;
     use std::thread;
     use std::time::Duration;
-    use super::super::raft::rpc::Client;
+    use super::super::raft::persister::Persister;
+    use super::super::raft::rpc::{self, Client};
+    use super::linearizability::{self, History, Input};
+    use super::common::{prefix_end, EventOp, Guard, RespErr, ScanArgs, ScanPage, TxnOp};
+    use bincode::serialize;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use rand::Rng;
+    use rand::seq::SliceRandom;
+    use std::env;
+    use std::fs;
 
     #[test]
     fn kv_basic() {
-        let addrs = get_addrs(5);
-        let mut clients = Vec::new();
-        for i in (0..5) {
-            let addrs2 = addrs.clone();
-            thread::spawn(move||{
-                server::KVServer::new(i as i32, &addrs2);
-            });
-            clients.push(Client{end_name: String::from(""), server_addr: addrs[i].clone()});
-        }
-        thread::sleep(Duration::from_millis(2000));
+        let (clients, _) = start_cluster("kv_basic", 5, None, None);
         let mut clerk = client::Clerk::new(&clients, 0);
         println!("---------------------put key: key---------------------");
         clerk.put(&String::from("key"), &String::from("value"));
         let v = clerk.get(&String::from("key"));
         println!("get value: {}", v);
-        thread::sleep(Duration::from_secs(60));
+        assert_eq!(v, "value");
+
+        // a deleted key is gone, unlike one holding an empty value
+        clerk.put("empty", "");
+        assert_eq!(clerk.lookup("empty"), Some(String::new()));
+        clerk.delete("key");
+        assert_eq!(clerk.lookup("key"), None);
+        assert_eq!(clerk.get("key"), "");
+        clerk.delete("key");
+        clerk.append("key", "a");
+        assert_eq!(clerk.lookup("key"), Some(String::from("a")));
+
+        // keys and values need not be utf-8
+        let key = [0xff, 0x00, 0x80];
+        clerk.put_bytes(&key, &[0xc3, 0x28]);
+        clerk.append_bytes(&key, &[0x00]);
+        assert_eq!(clerk.get_bytes(&key), Some(vec![0xc3, 0x28, 0x00]));
+        assert_eq!(clerk.compare_and_swap_bytes(&key, &[0xc3, 0x28, 0x00], &[0xfe]), (true, Some(vec![0xfe])));
+        assert_eq!(clerk.get_bytes(b"key"), Some(b"a".to_vec()));
     }
 
     #[test]
     fn kv_one_node_failed() {
-        let addrs = get_addrs(5);
+        let addrs = get_addrs("kv_one_node_failed", 5);
         let mut clients = Vec::new();
-        for i in (0..3) {
+        for i in 0..3 {
             let addrs2 = addrs.clone();
             thread::spawn(move||{
-                server::KVServer::new(i as i32, &addrs2);
+                server::KVServer::new(i, &addrs2, make_persister("kv_one_node_failed", i), None, None);
             });
         }
-        for i in (0..5) {
-            clients.push(Client{end_name: String::from(""), server_addr: addrs[i].clone()});
+        for addr in &addrs {
+            clients.push(Client{end_name: String::from(""), server_addr: addr.clone()});
         }
         thread::sleep(Duration::from_millis(2000));
         let mut clerk = client::Clerk::new(&clients, 0);
@@ -49,37 +70,521 @@ mod tests {
         clerk.put(&String::from("key1"), &String::from("value1"));
         let v = clerk.get(&String::from("key1"));
         println!("---------------------get key1 value: {}----------", v);
+        assert_eq!(v, "value1");
         let addrs2 = addrs.clone();
         thread::spawn(move||{
-            server::KVServer::new(3, &addrs2);
+            server::KVServer::new(3, &addrs2, make_persister("kv_one_node_failed", 3), None, None);
         });
         println!("---------------------put key: key2---------------------");
         clerk.put(&String::from("key2"), &String::from("value2"));
         let v = clerk.get(&String::from("key2"));
         println!("---------------------get key2 value: {}----------", v);
+        assert_eq!(v, "value2");
         let v = clerk.get(&String::from("key1"));
         println!("---------------------get key1 value: {}----------", v);
+        assert_eq!(v, "value1");
         let addrs2 = addrs.clone();
         thread::spawn(move||{
-            server::KVServer::new(4, &addrs2);
+            server::KVServer::new(4, &addrs2, make_persister("kv_one_node_failed", 4), None, None);
         });
         println!("---------------------put key: key3---------------------");
         clerk.put(&String::from("key3"), &String::from("value3"));
         let v = clerk.get(&String::from("key3"));
         println!("---------------------get key3 value: {}----------", v);
+        assert_eq!(v, "value3");
         let v = clerk.get(&String::from("key1"));
         println!("---------------------get key1 value: {}----------", v);
-        
-        thread::sleep(Duration::from_secs(60));
+        assert_eq!(v, "value1");
+    }
+
+    #[test]
+    fn kv_snapshot() {
+        let addrs = get_addrs("kv_snapshot", 3);
+        let maxraftstate = 1000;
+        let mut persisters = Vec::new();
+        let mut clients = Vec::new();
+        for (i, addr) in addrs.iter().enumerate() {
+            persisters.push(make_persister("kv_snapshot", i as i32));
+            clients.push(Client{end_name: String::from(""), server_addr: addr.clone()});
+        }
+        // the last server starts late and has to catch up from a snapshot
+        for (i, persister) in persisters.iter().enumerate().take(2) {
+            let addrs2 = addrs.clone();
+            let persister = persister.clone();
+            thread::spawn(move||{
+                server::KVServer::new(i as i32, &addrs2, persister, Some(maxraftstate), None);
+            });
+        }
+        thread::sleep(Duration::from_millis(2000));
+        let mut clerk = client::Clerk::new(&clients, 0);
+        for i in 0..30 {
+            clerk.put(&format!("k{}", i), &format!("v{}", i));
+        }
+        // a big value, both in the log and the snapshot
+        let big = "x".repeat(10000);
+        clerk.put("big", &big);
+        let addrs2 = addrs.clone();
+        let persister = persisters[2].clone();
+        thread::spawn(move||{
+            server::KVServer::new(2, &addrs2, persister, Some(maxraftstate), None);
+        });
+        for i in 30..40 {
+            clerk.append(&format!("k{}", i % 10), "x");
+        }
+        for i in 0..10 {
+            assert_eq!(clerk.get(&format!("k{}", i)), format!("v{}x", i));
+        }
+        for i in 10..30 {
+            assert_eq!(clerk.get(&format!("k{}", i)), format!("v{}", i));
+        }
+        assert_eq!(clerk.get("big"), big);
+
+        thread::sleep(Duration::from_millis(2000));
+        for p in &persisters {
+            assert!(!p.read_snapshot().is_empty());
+            assert!(p.raft_state_size() < 2 * maxraftstate);
+        }
+    }
+
+    #[test]
+    fn kv_membership() {
+        let addrs = get_addrs("kv_membership", 4);
+        let mut clients = Vec::new();
+        for i in 0..3 {
+            let addrs2 = addrs[..3].to_vec();
+            thread::spawn(move||{
+                server::KVServer::new(i, &addrs2, make_persister("kv_membership", i), None, None);
+            });
+            clients.push(Client{end_name: String::from(""), server_addr: addrs[i as usize].clone()});
+        }
+        thread::sleep(Duration::from_millis(2000));
+        let mut clerk = client::Clerk::new(&clients, 0);
+        clerk.put("a", "1");
+
+        // grow to four servers
+        let addrs2 = addrs.clone();
+        thread::spawn(move||{
+            server::KVServer::join(3, &addrs2, make_persister("kv_membership", 3), None, None);
+        });
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(clerk.add_server(3, &addrs[3]), Ok(()));
+        clerk.put("b", "2");
+        assert_eq!(clerk.get("a"), "1");
+
+        // and shrink again, the cluster keeps working without server 0 and 1
+        assert_eq!(clerk.remove_server(0), Ok(()));
+        assert_eq!(clerk.remove_server(1), Ok(()));
+        clerk.append("b", "3");
+        assert_eq!(clerk.get("a"), "1");
+        assert_eq!(clerk.get("b"), "23");
+        assert_eq!(clerk.remove_server(1), Ok(()));
+    }
+
+    #[test]
+    fn kv_read_index() {
+        let (clients, persisters) = start_cluster("kv_read_index", 3, None, None);
+        let mut clerk = client::Clerk::new(&clients, 0);
+        clerk.put("a", "1");
+        thread::sleep(Duration::from_millis(500));
+
+        // reads are served without appending to the log
+        let sizes: Vec<usize> = persisters.iter().map(|p| p.raft_state_size()).collect();
+        for _ in 0..20 {
+            assert_eq!(clerk.get("a"), "1");
+        }
+        assert_eq!(clerk.get("b"), "");
+        thread::sleep(Duration::from_millis(500));
+        let sizes2: Vec<usize> = persisters.iter().map(|p| p.raft_state_size()).collect();
+        assert_eq!(sizes, sizes2);
+
+        // and still see every write that finished before them
+        clerk.append("a", "2");
+        assert_eq!(clerk.get("a"), "12");
+    }
+
+    #[test]
+    fn kv_scan() {
+        let (clients, _) = start_cluster("kv_scan", 3, None, None);
+        let mut clerk = client::Clerk::new(&clients, 0);
+        for key in &["user/12/a", "user/123/a", "user/123/b", "user/123/c", "user/124/a", "user/2"] {
+            clerk.put(key, &key.to_uppercase());
+        }
+        let keys = |page: &ScanPage| -> Vec<String> {
+            page.entries.iter().map(|(k, _)| String::from_utf8(k.clone()).unwrap()).collect()
+        };
+
+        // a prefix page by page
+        let page = clerk.scan_prefix(b"user/123/", 2);
+        assert_eq!(keys(&page), vec!["user/123/a", "user/123/b"]);
+        assert_eq!(page.entries[0].1, b"USER/123/A".to_vec());
+        let page = clerk.scan_next(&page.next.unwrap(), 2).unwrap();
+        assert_eq!(keys(&page), vec!["user/123/c"]);
+        assert_eq!(page.next, None);
+
+        // a range, end excluded, and everything from a key on
+        let page = clerk.scan(b"user/123/b", Some(b"user/124/a"), 0);
+        assert_eq!(keys(&page), vec!["user/123/b", "user/123/c"]);
+        let page = clerk.scan(b"user/124", None, 10);
+        assert_eq!(keys(&page), vec!["user/124/a", "user/2"]);
+        assert_eq!(page.next, None);
+
+        // writes show up in the pages after them
+        let page = clerk.scan_prefix(b"user/", 3);
+        clerk.put("user/123/cc", "x");
+        clerk.delete("user/124/a");
+        let page = clerk.scan_next(&page.next.unwrap(), 3).unwrap();
+        assert_eq!(keys(&page), vec!["user/123/c", "user/123/cc", "user/2"]);
+
+        // an empty or inverted range has nothing in it, servers included
+        assert_eq!(clerk.scan(b"user/2", Some(b"user/1"), 10).entries, Vec::new());
+        assert_eq!(clerk.scan(b"user/2", Some(b"user/2"), 10).entries, Vec::new());
+        let args = ScanArgs { start: b"user/2".to_vec(), end: Some(b"user/1".to_vec()), limit: 10, revision: 0 };
+        for client in &clients {
+            let _ = client.call(String::from("KV.Scan"), serialize(&args).unwrap());
+        }
+        assert_eq!(keys(&clerk.scan_prefix(b"user/2", 10)), vec!["user/2"]);
+
+        assert_eq!(prefix_end(b"a\xff\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_end(b"\xff"), None);
     }
 
-    fn get_addrs(server_num: usize) -> Vec<String> {
-        let mut port = 7000;
+    #[test]
+    fn kv_ttl() {
+        let (clients, persisters) = start_cluster("kv_ttl", 3, None, None);
+        let mut clerk = client::Clerk::new(&clients, 0);
+        let ttl = Duration::from_millis(500);
+        clerk.put_with_ttl("session", "s", ttl);
+        clerk.put_with_ttl("bucket", "1", ttl);
+        clerk.append("bucket", "2");
+        clerk.put_with_ttl("kept", "k", ttl);
+        clerk.put("kept", "k2");
+        assert_eq!(clerk.get("session"), "s");
+        assert_eq!(clerk.get("bucket"), "12");
+        let page = clerk.scan(b"", None, 0);
+        assert_eq!(page.entries.len(), 3);
+
+        // appends keep the expiry, puts drop it
+        let sizes: Vec<usize> = persisters.iter().map(|p| p.raft_state_size()).collect();
+        thread::sleep(Duration::from_millis(600));
+        assert_eq!(clerk.lookup("session"), None);
+        assert_eq!(clerk.lookup("bucket"), None);
+        assert_eq!(clerk.get("kept"), "k2");
+        let page = clerk.scan(b"", None, 0);
+        assert_eq!(page.entries, vec![(b"kept".to_vec(), b"k2".to_vec())]);
+
+        // nobody wrote since, the log only grew by the leader's sweep
+        thread::sleep(Duration::from_millis(1500));
+        for (p, size) in persisters.iter().zip(sizes) {
+            assert!(p.raft_state_size() > size);
+        }
+        assert_eq!(clerk.lookup("bucket"), None);
+        assert_eq!(clerk.put_if_absent("session", "t"), (true, Some(String::from("t"))));
+        assert_eq!(clerk.get("session"), "t");
+    }
+
+    #[test]
+    fn kv_compare_and_swap() {
+        let (clients, _) = start_cluster("kv_compare_and_swap", 3, None, None);
+        let mut clerk = client::Clerk::new(&clients, 0);
+
+        // a lock: only one holder, released only by whoever holds it
+        assert_eq!(clerk.put_if_absent("lock", "a"), (true, Some(String::from("a"))));
+        assert_eq!(clerk.put_if_absent("lock", "b"), (false, Some(String::from("a"))));
+        assert_eq!(clerk.delete_if_equal("lock", "b"), (false, Some(String::from("a"))));
+        assert_eq!(clerk.delete_if_equal("lock", "a"), (true, None));
+        assert_eq!(clerk.compare_and_swap("lock", "a", "b"), (false, None));
+
+        // a counter bumped by clerks racing each other loses no increment
+        clerk.put("n", "0");
+        let mut handles = Vec::new();
+        for c in 1..=3 {
+            let clients = clients.clone();
+            handles.push(thread::spawn(move || {
+                let mut clerk = client::Clerk::new(&clients, c);
+                for _ in 0..5 {
+                    let mut n = clerk.get("n");
+                    loop {
+                        let next = (n.parse::<u32>().unwrap() + 1).to_string();
+                        match clerk.compare_and_swap("n", &n, &next) {
+                            (true, _) => break,
+                            (false, current) => n = current.unwrap(),
+                        }
+                    }
+                }
+            }));
+        }
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(clerk.get("n"), "15");
+    }
+
+    #[test]
+    fn kv_txn() {
+        let (clients, _) = start_cluster("kv_txn", 3, None, None);
+        let mut clerk = client::Clerk::new(&clients, 0);
+
+        clerk.put("a", "1");
+        let outcome = clerk.txn()
+            .when(Guard::equals("a", "1"))
+            .when(Guard::absent("b"))
+            .then(TxnOp::put("b", "2"))
+            .then(TxnOp::append("b", "3"))
+            .then(TxnOp::delete("a"))
+            .or_else(TxnOp::put("failed", "1"))
+            .commit();
+        assert!(outcome.succeeded);
+        assert_eq!(outcome.guards, vec![true, true]);
+        assert_eq!(clerk.lookup("a"), None);
+        assert_eq!(clerk.get("b"), "23");
+
+        // one guard failing runs the other branch, and says which failed
+        let outcome = clerk.txn()
+            .when(Guard::exists("b"))
+            .when(Guard::exists("a"))
+            .then(TxnOp::delete("b"))
+            .or_else(TxnOp::put("failed", "1"))
+            .commit();
+        assert!(!outcome.succeeded);
+        assert_eq!(outcome.guards, vec![true, false]);
+        assert_eq!(clerk.get("b"), "23");
+        assert_eq!(clerk.get("failed"), "1");
+
+        // transfers between two accounts racing each other keep the total
+        clerk.put("x", "10");
+        clerk.put("y", "10");
+        let mut handles = Vec::new();
+        for c in 1..=3 {
+            let clients = clients.clone();
+            handles.push(thread::spawn(move || {
+                let mut clerk = client::Clerk::new(&clients, c);
+                for _ in 0..3 {
+                    loop {
+                        let x = clerk.get("x");
+                        let y = clerk.get("y");
+                        let (nx, ny) = (x.parse::<u32>().unwrap() - 1, y.parse::<u32>().unwrap() + 1);
+                        let outcome = clerk.txn()
+                            .when(Guard::equals("x", &x))
+                            .when(Guard::equals("y", &y))
+                            .then(TxnOp::put("x", nx.to_string()))
+                            .then(TxnOp::put("y", ny.to_string()))
+                            .commit();
+                        if outcome.succeeded {
+                            break;
+                        }
+                    }
+                }
+            }));
+        }
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(clerk.get("x"), "1");
+        assert_eq!(clerk.get("y"), "19");
+    }
+
+    #[test]
+    fn kv_watch() {
+        let maxraftstate = 5000;
+        let (clients, _) = start_cluster("kv_watch", 3, Some(maxraftstate), None);
+        let mut clerk = client::Clerk::new(&clients, 0);
+        clerk.put("cfg/old", "0");
+
+        let mut watcher = clerk.watch_prefix(b"cfg/", 0);
+        clerk.put("cfg/a", "1");
+        clerk.put("other", "x");
+        clerk.append("cfg/a", "2");
+        clerk.txn().then(TxnOp::delete("cfg/a")).then(TxnOp::put("cfg/b", "3")).commit();
+        let mut events = Vec::new();
+        while events.len() < 4 {
+            events.extend(watcher.recv().unwrap());
+        }
+        let changes: Vec<(String, Option<String>, EventOp)> = events.iter()
+            .map(|e| (String::from_utf8(e.key.clone()).unwrap(), e.value.clone().map(|v| String::from_utf8(v).unwrap()), e.op))
+            .collect();
+        assert_eq!(changes, vec![
+            ("cfg/a".to_string(), Some("1".to_string()), EventOp::Put),
+            ("cfg/a".to_string(), Some("12".to_string()), EventOp::Put),
+            ("cfg/a".to_string(), None, EventOp::Delete),
+            ("cfg/b".to_string(), Some("3".to_string()), EventOp::Put),
+        ]);
+        // both changes of the transaction were made by the same entry
+        assert!(events[0].index < events[1].index && events[1].index < events[2].index);
+        assert_eq!(events[2].index, events[3].index);
+
+        // a new watcher resumes from any index it was handed
+        let mut resumed = clerk.watch(b"cfg/a", events[1].index);
+        let again = resumed.recv().unwrap();
+        assert_eq!(again, events[1..3].to_vec());
+        clerk.put("cfg/b", "4");
+        let next = watcher.recv().unwrap();
+        assert_eq!(next.len(), 1);
+        assert_eq!(next[0].value, Some(b"4".to_vec()));
+        assert_eq!(watcher.next_index(), next[0].index + 1);
+
+        // a big put makes the servers snapshot, the changes stay
+        clerk.put("big", &"x".repeat(2 * maxraftstate));
+        thread::sleep(Duration::from_millis(500));
+        let mut old = clerk.watch(b"cfg/a", events[0].index);
+        assert_eq!(old.recv().unwrap(), events[0..3].to_vec());
+
+        // compacting drops the changes before the revision
+        clerk.compact(events[2].index);
+        let mut old = clerk.watch_prefix(b"cfg/", events[0].index);
+        assert_eq!(old.recv(), Err(RespErr::ErrCompacted));
+        let mut kept = clerk.watch_prefix(b"cfg/", events[2].index);
+        let mut rest = events[2..4].to_vec();
+        rest.extend(next);
+        assert_eq!(kept.recv().unwrap(), rest);
+    }
+
+    #[test]
+    fn kv_mvcc() {
+        let max_revisions = 40;
+        let (clients, _) = start_cluster("kv_mvcc", 3, None, Some(max_revisions));
+        let mut clerk = client::Clerk::new(&clients, 0);
+
+        clerk.put("a", "1");
+        clerk.put("b", "x");
+        let first = clerk.get_versioned(b"a").unwrap();
+        assert_eq!((first.value.as_slice(), first.version), (&b"1"[..], 1));
+        clerk.append("a", "2");
+        let second = clerk.get_versioned(b"a").unwrap();
+        assert_eq!((second.value.as_slice(), second.version), (&b"12"[..], 2));
+        assert!(second.mod_revision > first.mod_revision);
+        let before_delete = clerk.scan(b"", None, 0).revision;
+        clerk.delete("a");
+        clerk.delete("b");
+        assert_eq!(clerk.get_versioned(b"a"), None);
+        // a key created again starts over
+        clerk.put("a", "3");
+        assert_eq!(clerk.get_versioned(b"a").unwrap().version, 1);
+
+        assert_eq!(clerk.get_at(b"a", first.mod_revision), Ok(Some(first.clone())));
+        assert_eq!(clerk.get_at(b"a", second.mod_revision - 1), Ok(Some(first.clone())));
+        assert_eq!(clerk.get_at(b"a", before_delete), Ok(Some(second.clone())));
+        assert_eq!(clerk.get_at(b"a", first.mod_revision - 1), Ok(None));
+        assert_eq!(clerk.get_at(b"a", 1 << 40), Err(RespErr::ErrFutureRevision));
+
+        // every page of a scan at a revision sees the same keys
+        let page = clerk.scan_at(b"", None, 1, before_delete).unwrap();
+        assert_eq!(page.entries, vec![(b"a".to_vec(), b"12".to_vec())]);
+        clerk.put("c", "y");
+        let page = clerk.scan_next(&page.next.unwrap(), 1).unwrap();
+        assert_eq!(page.entries, vec![(b"b".to_vec(), b"x".to_vec())]);
+        assert_eq!(page.next, None);
+
+        // a transaction changes each key it writes by one version
+        clerk.txn().then(TxnOp::put("c", "z")).then(TxnOp::append("c", "!")).commit();
+        let c = clerk.get_versioned(b"c").unwrap();
+        assert_eq!((c.value.as_slice(), c.version), (&b"z!"[..], 2));
+        clerk.txn().then(TxnOp::delete("c")).then(TxnOp::put("c", "w")).commit();
+        let c = clerk.get_versioned(b"c").unwrap();
+        assert_eq!((c.value.as_slice(), c.version), (&b"w"[..], 3));
+
+        clerk.compact(second.mod_revision);
+        assert_eq!(clerk.get_at(b"a", first.mod_revision), Err(RespErr::ErrCompacted));
+        assert_eq!(clerk.get_at(b"a", second.mod_revision), Ok(Some(second.clone())));
+        assert_eq!(clerk.scan_at(b"", None, 0, first.mod_revision).map(|p| p.entries), Err(RespErr::ErrCompacted));
+
+        // the leader compacts once there are more than max_revisions
+        let puts = max_revisions + 2;
+        for i in 0..puts {
+            clerk.put("a", &i.to_string());
+        }
+        thread::sleep(Duration::from_millis(2000));
+        assert_eq!(clerk.get_at(b"a", before_delete), Err(RespErr::ErrCompacted));
+        let latest = clerk.get_versioned(b"a").unwrap();
+        assert_eq!(latest.version, 1 + puts as u64);
+        assert_eq!(clerk.get_at(b"a", latest.mod_revision - 1).unwrap().unwrap().value, (puts - 2).to_string().into_bytes());
+
+        // and then leaves an idle cluster alone
+        let revision = clerk.scan(b"", None, 0).revision;
+        thread::sleep(Duration::from_millis(3000));  // a few sweeps
+        assert_eq!(clerk.scan(b"", None, 0).revision, revision);
+    }
+
+    #[test]
+    fn kv_linearizable() {
+        let (clients, _) = start_cluster("kv_linearizable", 5, None, None);
+        let nets: Vec<rpc::ANetwork> = clients.iter().map(|c| rpc::mem_network(&c.server_addr).unwrap()).collect();
+
+        // clerks hammer a few keys while the servers are split up now and then
+        let history = Arc::new(History::new());
+        let done = Arc::new(AtomicBool::new(false));
+        let mut handles = Vec::new();
+        for c in 1..=3 {
+            let clients = clients.clone();
+            let history = history.clone();
+            let done = done.clone();
+            handles.push(thread::spawn(move || {
+                let mut clerk = client::Clerk::new(&clients, c);
+                let mut rng = rand::thread_rng();
+                let mut n = 0;
+                while !done.load(Ordering::SeqCst) {
+                    let key = format!("k{}", rng.gen_range(0, 2));
+                    let value = format!("{}.{} ", c, n);
+                    n += 1;
+                    match rng.gen_range(0, 4) {
+                        0 => history.record(c, &key, Input::Get, || clerk.get(&key)),
+                        1 => history.record(c, &key, Input::Put(value.clone()), || { clerk.put(&key, &value); String::new() }),
+                        2 => history.record(c, &key, Input::Delete, || { clerk.delete(&key); String::new() }),
+                        _ => history.record(c, &key, Input::Append(value.clone()), || { clerk.append(&key, &value); String::new() }),
+                    };
+                }
+            }));
+        }
+        let mut rng = rand::thread_rng();
+        for _ in 0..4 {
+            thread::sleep(Duration::from_millis(1000));
+            let mut ids: Vec<i32> = (0..5).collect();
+            ids.shuffle(&mut rng);
+            rpc::partition(&nets, &[ids[..2].to_vec(), ids[2..].to_vec()]);
+        }
+        thread::sleep(Duration::from_millis(1000));
+        rpc::partition(&nets, &[(0..5).collect()]);
+        done.store(true, Ordering::SeqCst);
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        let ops = history.operations();
+        assert!(ops.len() > 10, "only {} operations finished", ops.len());
+        if let Err(bad) = linearizability::check(&ops) {
+            let lines: Vec<String> = bad.iter().map(|op| op.to_string()).collect();
+            panic!("history is not linearizable:\n{}", lines.join("\n"));
+        }
+    }
+
+    // the servers of a test talk over in-process networks, no ports involved
+    fn get_addrs(test: &str, server_num: usize) -> Vec<String> {
         let mut addrs = Vec::new();
-        for i in (0..server_num) {
-            addrs.push(format!("127.0.0.1:{}", port));
-            port += 1;
+        for i in 0..server_num {
+            addrs.push(format!("mem:{}-{}", test, i));
         }
-        return addrs;
+        addrs
+    }
+
+    // start n servers from empty data directories and wait until they have
+    // elected a leader, which then serves reads.
+    fn start_cluster(test: &str, n: usize, maxraftstate: Option<usize>, max_revisions: Option<usize>) -> (Vec<Client>, Vec<Persister>) {
+        let addrs = get_addrs(test, n);
+        let mut clients = Vec::new();
+        let mut persisters = Vec::new();
+        for (i, addr) in addrs.iter().enumerate() {
+            let persister = make_persister(test, i as i32);
+            server::KVServer::new(i as i32, &addrs, persister.clone(), maxraftstate, max_revisions);
+            persisters.push(persister);
+            clients.push(Client{end_name: String::from(""), server_addr: addr.clone()});
+        }
+        client::Clerk::new(&clients, u64::MAX).get_bytes(b"");
+        (clients, persisters)
+    }
+
+    // every test starts from an empty data directory
+    fn make_persister(test: &str, id: i32) -> Persister {
+        let dir = env::temp_dir().join(format!("kv-service-{}-{}", test, id));
+        let _ = fs::remove_dir_all(&dir);
+        Persister::new(dir.to_str().unwrap())
     }
 }
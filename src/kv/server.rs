@@ -1,97 +1,367 @@
-use std::time::Duration;
-use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ops::Bound;
 use std::thread;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
 use std::sync::mpsc::{self, SyncSender, Receiver, RecvTimeoutError};
-use super::super::raft::{Raft, ApplyMsg};
-use super::super::raft::rpc::Client;
+use super::super::raft::{Raft, ApplyMsg, ConfigError};
+use super::super::raft::persister::Persister;
+use super::super::raft::rpc::{ANetwork, Client, Service};
 use super::common::*;
 use bincode::{serialize, deserialize};
 
 const START_TIMEOUT_INTERVAL: u64 = 5000; // ms
+const MAX_SCAN_LIMIT: usize = 1000;       // entries per scan page, keeps replies small
+const SWEEP_INTERVAL: u64 = 1000;         // ms between a leader's checks for expired keys
+const WATCH_WAIT: u64 = 1000;             // ms a watch waits for changes before replying with none
+const DEFAULT_MAX_REVISIONS: usize = 10000; // old versions and changes kept when no max_revisions is given
+
+type CondResult = (u64, bool, Option<Vec<u8>>);  // seq of a conditional write, whether it held and the value after it
+type TxnResult = (u64, Vec<bool>);                // seq of a transaction and whether each of its guards held
+
+// a key as written by the entry at revision. value is None once it was deleted.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Version {
+    revision: usize,
+    version: u64,
+    value: Option<Vec<u8>>,
+}
 
 struct NotifyArgs {
     term: u64,
-    value: String,
+    value: Option<Vec<u8>>,
+    ok: bool,   // whether a conditional write's condition held
+    guards: Vec<bool>,  // whether each guard of a transaction held
     err: RespErr,
 }
 
+impl NotifyArgs {
+    // the request did not go through.
+    fn failed(err: RespErr) -> NotifyArgs {
+        NotifyArgs{term: 0, value: None, ok: false, guards: Vec::new(), err}
+    }
+}
+
 pub struct KVServer {
     rf: Arc<Mutex<Raft>>,
+    persister: Persister,
+    maxraftstate: Option<usize>,    // snapshot once raft state grows this big, None to never snapshot
+    max_revisions: usize,           // keep old versions and watch history for this many revisions
+    snapshot_ch: SyncSender<(usize, Vec<u8>)>,
 
-    data: HashMap<String, String>,
-    cache: HashMap<u64, u64>,
+    data: BTreeMap<Vec<u8>, Vec<Version>>,  // key -> its versions, oldest first
+    compacted: usize,           // reads before this revision are gone
+    cache: HashMap<u64, u64>,   // client id -> last applied request seq
+    cond_results: HashMap<u64, CondResult>,  // client id -> its last conditional write
+    txn_results: HashMap<u64, TxnResult>,    // client id -> its last transaction
+    expiry: HashMap<Vec<u8>, u64>,  // key -> when it expires, ms since the unix epoch
+    clock: u64,                 // latest leader clock logged, expiry in the state machine goes by it
     notify_ch_map: HashMap<usize, SyncSender<NotifyArgs>>,
+    last_applied: usize,
+
+    // changes applied from index history_start on, oldest first. compacting
+    // drops the ones reads can't see anymore either.
+    history: VecDeque<WatchEvent>,
+    history_start: usize,
+    applied: Arc<Condvar>,  // notified after every applied entry, watches wait on it
 }
 
 impl KVServer {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(
         id: i32,
-        addrs: &Vec<String>,
-//        maxraftstate: u64,
+        addrs: &[String],
+        persister: Persister,
+        maxraftstate: Option<usize>,
+        max_revisions: Option<usize>,
         ) -> Client {
         let (s, r) = mpsc::sync_channel(1000);
-        let (rf, client, reply_sender, req_recv)= Raft::new(id, addrs, &s);
-        let kv = KVServer {
+        let raft = Raft::new(id, addrs, persister.clone(), &s);
+        Self::serve(raft, r, persister, maxraftstate, max_revisions)
+    }
+
+    // start a server which joins a running cluster, it only serves requests
+    // after being added with Clerk::add_server. addrs[id] is its own address.
+    pub fn join(
+        id: i32,
+        addrs: &[String],
+        persister: Persister,
+        maxraftstate: Option<usize>,
+        max_revisions: Option<usize>,
+        ) -> Client {
+        let (s, r) = mpsc::sync_channel(1000);
+        let raft = Raft::join(id, addrs, persister.clone(), &s);
+        Self::serve(raft, r, persister, maxraftstate, max_revisions)
+    }
+
+    fn serve(
+        raft: (Arc<Mutex<Raft>>, Client, ANetwork),
+        r: Receiver<ApplyMsg>,
+        persister: Persister,
+        maxraftstate: Option<usize>,
+        max_revisions: Option<usize>,
+        ) -> Client {
+        let (rf, client, network) = raft;
+
+        // raft persists snapshots under its lock, hand them to another thread
+        // so run() keeps applying meanwhile
+        let (snapshot_ch, snapshot_recv) = mpsc::sync_channel(1);
+        let rf1 = rf.clone();
+        thread::spawn(move || {
+            for (index, snapshot) in snapshot_recv {
+                Raft::snapshot(rf1.clone(), index, snapshot);
+            }
+        });
+
+        let mut kv = KVServer {
             rf,
-            data: HashMap::new(),
+            persister,
+            maxraftstate,
+            max_revisions: max_revisions.unwrap_or(DEFAULT_MAX_REVISIONS),
+            snapshot_ch,
+            data: BTreeMap::new(),
+            compacted: 0,
             cache: HashMap::new(),
+            cond_results: HashMap::new(),
+            txn_results: HashMap::new(),
+            expiry: HashMap::new(),
+            clock: 0,
             notify_ch_map: HashMap::new(),
+            last_applied: 0,
+            history: VecDeque::new(),
+            history_start: 1,
+            applied: Arc::new(Condvar::new()),
         };
+        let snapshot = kv.persister.read_snapshot();
+        kv.restore_snapshot(&snapshot);
         let kv = Arc::new(Mutex::new(kv));
-        Self::register_callback(&kv, reply_sender, req_recv);
+        network.add_service(Self::make_service(&kv));
+        let kv1 = kv.clone();
+        thread::spawn(move || { Self::sweep(kv1); });
         thread::spawn(move || { Self::run(kv, r); });
         client
     }
 
     pub fn get(mu: Arc<Mutex<KVServer>>, args: &ReqArgs) -> GetReply {
-        let args = serialize(args).unwrap();
-        let (err, value) = Self::start(mu, &args);
-        GetReply{err, value}
+        Self::get_at(mu, &GetAtArgs{key: args.command.key().to_vec(), revision: 0})
+    }
+
+    pub fn get_at(mu: Arc<Mutex<KVServer>>, args: &GetAtArgs) -> GetReply {
+        let result = Self::read(mu, |kv| {
+            let revision = kv.check_revision(args.revision)?;
+            Ok(kv.version_at(&args.key, revision).cloned())
+        });
+        match result.and_then(|r| r) {
+            Ok(Some(Version{revision, version, value})) => GetReply{err: RespErr::OK, value, version, mod_revision: revision},
+            Ok(None) => GetReply{err: RespErr::OK, value: None, version: 0, mod_revision: 0},
+            Err(err) => GetReply{err, value: None, version: 0, mod_revision: 0},
+        }
+    }
+
+    // the entries in [start, end) from start on, limit at most. if there are
+    // more, next says where the following page starts.
+    pub fn scan(mu: Arc<Mutex<KVServer>>, args: &ScanArgs) -> ScanReply {
+        let limit = if args.limit == 0 { MAX_SCAN_LIMIT } else { args.limit.min(MAX_SCAN_LIMIT) };
+        let end = match args.end {
+            Some(ref end) => Bound::Excluded(&end[..]),
+            None => Bound::Unbounded,
+        };
+        let result = Self::read(mu, |kv| {
+            let revision = kv.check_revision(args.revision)?;
+            // an empty range, BTreeMap::range would panic under our lock
+            if args.end.as_ref().is_some_and(|end| end <= &args.start) {
+                return Ok((Vec::new(), None, revision));
+            }
+            let mut entries: Vec<(Vec<u8>, Vec<u8>)> = kv.data.range::<[u8], _>((Bound::Included(&args.start[..]), end))
+                .filter_map(|(k, _)| kv.version_at(k, revision).and_then(|v| v.value.clone()).map(|v| (k.clone(), v)))
+                .take(limit + 1)
+                .collect();
+            let next = if entries.len() > limit { entries.pop().map(|(k, _)| k) } else { None };
+            Ok((entries, next, revision))
+        });
+        match result.and_then(|r| r) {
+            Ok((entries, next, revision)) => ScanReply{err: RespErr::OK, entries, next, revision},
+            Err(err) => ScanReply{err, entries: Vec::new(), next: None, revision: args.revision},
+        }
+    }
+
+    // the changes args asks for, waiting a while for some if there are none
+    // yet. the reply says where to watch from next.
+    pub fn watch(mu: Arc<Mutex<KVServer>>, args: &WatchArgs) -> WatchReply {
+        // makes sure we are the leader and have applied all it committed
+        let applied = match Self::read(mu.clone(), |kv| kv.last_applied) {
+            Ok(applied) => applied,
+            Err(err) => return WatchReply{err, events: Vec::new(), next: args.from_index},
+        };
+        if args.from_index == 0 {
+            return WatchReply{err: RespErr::OK, events: Vec::new(), next: applied + 1};
+        }
+        let deadline = Instant::now() + Duration::from_millis(WATCH_WAIT);
+        let applied = mu.lock().unwrap().applied.clone();
+        let mut kv = mu.lock().unwrap();
+        // nothing before from matched, each wakeup only looks at what is new
+        let mut from = args.from_index;
+        loop {
+            if from < kv.history_start {
+                return WatchReply{err: RespErr::ErrCompacted, events: Vec::new(), next: args.from_index};
+            }
+            let start = kv.history.partition_point(|e| e.index < from);
+            let events: Vec<WatchEvent> = kv.history.range(start..)
+                .filter(|e| args.matches(&e.key))
+                .cloned()
+                .collect();
+            from = from.max(kv.last_applied + 1);
+            let now = Instant::now();
+            if !events.is_empty() || now >= deadline {
+                return WatchReply{err: RespErr::OK, events, next: from};
+            }
+            kv = applied.wait_timeout(kv, deadline - now).unwrap().0;
+        }
+    }
+
+    // reads don't go through the log: raft hands us a read index once it has
+    // confirmed its leadership, and we run f as soon as we applied that far.
+    fn read<F, R>(mu: Arc<Mutex<KVServer>>, f: F) -> Result<R, RespErr>
+        where F: Fn(&KVServer) -> R {
+        let rf = mu.lock().unwrap().rf.clone();
+        let index = match Raft::read_index(rf) {
+            Ok(index) => index,
+            Err(_) => return Err(RespErr::ErrWrongLeader),
+        };
+        let deadline = Instant::now() + Duration::from_millis(START_TIMEOUT_INTERVAL);
+        loop {
+            {
+                let kv = mu.lock().unwrap();
+                if kv.last_applied >= index {
+                    return Ok(f(&kv));
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(RespErr::ErrWrongLeader);
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    // whether key has not expired by now, or by the time the log got to if
+    // that is later.
+    fn live(&self, key: &[u8], now: u64) -> bool {
+        self.expiry.get(key).is_none_or(|&at| at > now.max(self.clock))
+    }
+
+    // the revision a read asking for revision is done at, if it still can be.
+    fn check_revision(&self, revision: usize) -> Result<usize, RespErr> {
+        if revision == 0 {
+            Ok(self.last_applied)
+        } else if revision < self.compacted {
+            Err(RespErr::ErrCompacted)
+        } else if revision > self.last_applied {
+            Err(RespErr::ErrFutureRevision)
+        } else {
+            Ok(revision)
+        }
+    }
+
+    // the version of key at revision, None if it did not exist then. keys
+    // which expired since read as absent at the latest revision.
+    fn version_at(&self, key: &[u8], revision: usize) -> Option<&Version> {
+        let versions = self.data.get(key)?;
+        let version = versions.iter().rev().find(|v| v.revision <= revision)?;
+        if version.value.is_none() || (revision == self.last_applied && !self.live(key, now_ms())) {
+            return None;
+        }
+        Some(version)
+    }
+
+    // the latest value of key.
+    fn value(&self, key: &[u8]) -> Option<&Vec<u8>> {
+        self.data.get(key).and_then(|versions| versions.last()).and_then(|v| v.value.as_ref())
     }
 
     pub fn put_append(mu: Arc<Mutex<KVServer>>, args: &ReqArgs) -> PutAppendReply {
-        let args = serialize(args).unwrap();
-        let (err, _) = Self::start(mu, &args);
-        PutAppendReply{err: err}
+        let err = Self::start(mu, &args.encode(now_ms())).err;
+        PutAppendReply{err}
     }
 
+    pub fn cas(mu: Arc<Mutex<KVServer>>, args: &ReqArgs) -> CasReply {
+        let result = Self::start(mu, &args.encode(now_ms()));
+        CasReply{err: result.err, ok: result.ok, value: result.value}
+    }
+
+    pub fn txn(mu: Arc<Mutex<KVServer>>, args: &ReqArgs) -> TxnReply {
+        let result = Self::start(mu, &args.encode(now_ms()));
+        TxnReply{err: result.err, succeeded: result.ok, guards: result.guards}
+    }
+
+    // add or remove a server, returns once the joint configuration is committed.
+    // raft finishes the change by switching to the new configuration on its own.
+    pub fn change_config(mu: Arc<Mutex<KVServer>>, args: &ConfigChangeArgs) -> ConfigChangeReply {
+        let err = Self::propose(mu, |rf| {
+            let result = match args.clone() {
+                ConfigChangeArgs::AddServer { id, addr } => Raft::add_server(rf, id, addr),
+                ConfigChangeArgs::RemoveServer { id } => Raft::remove_server(rf, id),
+            };
+            result.map_err(|err| match err {
+                ConfigError::NotLeader => RespErr::ErrWrongLeader,
+                ConfigError::InProgress => RespErr::ErrConfigInProgress,
+                // nothing to do, most likely a retry of a change which went through
+                ConfigError::AlreadyMember | ConfigError::NotMember => RespErr::OK,
+                ConfigError::EmptyConfiguration => RespErr::ErrConfigRejected,
+            })
+        }).err;
+        ConfigChangeReply{err}
+    }
+
+    // the waiter may have given up already, nobody gets the reply then.
     fn notify_if_present(&mut self, index: usize, reply: NotifyArgs) {
-        if let Some(sch) = self.notify_ch_map.get(&index) {
-            sch.send(reply).unwrap();
+        if let Some(sch) = self.notify_ch_map.remove(&index) {
+            let _ = sch.send(reply);
         }
-        self.notify_ch_map.remove(&index);
     }
 
-    fn start(mu: Arc<Mutex<KVServer>>, command: &Vec<u8>) -> (RespErr, String) {
-        let notify_ch: Receiver<NotifyArgs>;
-        let index;
-        let term;
+    fn start(mu: Arc<Mutex<KVServer>>, command: &[u8]) -> NotifyArgs {
+        Self::propose(mu, |rf| {
+            let (index, term, ok) = Raft::start(rf, command);
+            if !ok {
+                return Err(RespErr::ErrWrongLeader);
+            }
+            Ok((index, term))
+        })
+    }
+
+    // append an entry to the raft log with f and wait until it is applied.
+    // f returns the index and term of the new entry. we don't hold our lock
+    // while calling into raft, raft may be waiting for run() to take entries.
+    fn propose<F>(mu: Arc<Mutex<KVServer>>, f: F) -> NotifyArgs
+        where F: FnOnce(Arc<Mutex<Raft>>) -> Result<(usize, u64), RespErr> {
+        let rf = mu.lock().unwrap().rf.clone();
+        let (index, term) = match f(rf) {
+            Ok(entry) => entry,
+            Err(err) => return NotifyArgs::failed(err),
+        };
+        let (sh, notify_ch) = mpsc::sync_channel(1);
         {
             let mut kv = mu.lock().unwrap();
-            let (i, t, ok) = Raft::start(kv.rf.clone(), command);
-            index = i;
-            term = t;
-            if ok == false {
-                return (RespErr::ErrWrongLeader, String::from(""));
+            if kv.last_applied >= index {
+                // applied before we got here and its result is gone, the
+                // clerk's retry is answered from the duplicate table
+                return NotifyArgs::failed(RespErr::ErrWrongLeader);
             }
-            let (sh, rh) = mpsc::sync_channel(0);
-            notify_ch = rh;
             kv.notify_ch_map.insert(index, sh);
         }
         let d = Duration::from_millis(START_TIMEOUT_INTERVAL);
         match notify_ch.recv_timeout(d) {
             Ok(result) => {
                 if result.term != term {
-                    return (RespErr::ErrWrongLeader,  String::from(""));
+                    return NotifyArgs::failed(RespErr::ErrWrongLeader);
                 }
-                return (result.err, result.value);
+                result
             }
             Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
                 println!("---------------------start timeout---------------------");
                 let mut kv = mu.lock().unwrap();
                 kv.notify_ch_map.remove(&index);
-                return (RespErr::ErrWrongLeader,  String::from(""));
+                NotifyArgs::failed(RespErr::ErrWrongLeader)
             }
         }
     }
@@ -100,44 +370,269 @@ impl KVServer {
 //        println!("---------------apply");
         let mut result = NotifyArgs{
             term: msg.term,
-            value: String::from(""),
+            value: None,
+            ok: false,
+            guards: Vec::new(),
             err: RespErr::OK
         };
-        let args: ReqArgs = deserialize(&msg.command).unwrap();
-        if args.request_type == 0 {
-            let value = self.data.get(&args.key);
-            match value {
-                Some(v) => result.value = v.clone(),
-                None => result.value = String::from(""),
-            }
-        } else if args.request_type == 1 {
-            let seq = self.cache.get(&args.cliend_id);
-            let mut flag = true;
-            match seq {
-                Some(n) => {
-                    if *n >= args.request_seq {
-                        flag = false;
+        self.last_applied = msg.index;
+        let decoded = ReqArgs::decode(&msg.command);
+        if let Ok((ref args, now)) = decoded {
+            self.clock = self.clock.max(now);
+            self.expire(args.command.key());
+        }
+        match decoded {
+            // only in logs written before reads used read_index
+            Ok((ReqArgs { command: Command::Get { key }, .. }, _)) => result.value = self.value(&key).cloned(),
+            Ok((ReqArgs { command: Command::Sweep, .. }, _)) => {
+                let clock = self.clock;
+                let expired: Vec<Vec<u8>> = self.expiry.iter().filter(|(_, &at)| at <= clock).map(|(k, _)| k.clone()).collect();
+                for key in expired {
+                    self.expire(&key);
+                }
+            },
+            Ok((ReqArgs { command: Command::Compact { revision }, .. }, _)) => self.compact(revision),
+            Ok((args, _)) => {
+                if self.cache.get(&args.client_id).is_some_and(|&seq| seq >= args.request_seq) {
+                    if let Some((seq, ok, value)) = self.cond_results.get(&args.client_id) {
+                        // a retry of a conditional write, answer what it did the first time
+                        if *seq == args.request_seq {
+                            result.ok = *ok;
+                            result.value = value.clone();
+                        }
                     }
-                },
-                None => (),
-            }
-            if flag {
-                if args.op == "Put" {
-                    self.data.insert(args.key, args.value);
+                    if let Some((seq, guards)) = self.txn_results.get(&args.client_id) {
+                        if *seq == args.request_seq {
+                            result.ok = guards.iter().all(|&held| held);
+                            result.guards = guards.clone();
+                        }
+                    }
+                } else if let Command::Txn { guards, success, failure } = args.command {
+                    self.cache.insert(args.client_id, args.request_seq);
+                    result.guards = self.apply_txn(guards, success, failure);
+                    result.ok = result.guards.iter().all(|&held| held);
+                    self.txn_results.insert(args.client_id, (args.request_seq, result.guards.clone()));
                 } else {
-                    let value = self.data.get(&args.key);
-                    match value {
-                        Some(v) => self.data.insert(args.key, format!("{}{}", v, args.value)),
-                        None => self.data.insert(args.key, args.value),
-                    };
+                    self.cache.insert(args.client_id, args.request_seq);
+                    let conditional = matches!(args.command,
+                        Command::CompareAndSwap { .. } | Command::PutIfAbsent { .. } | Command::DeleteIfEqual { .. });
+                    let key = args.command.key().to_vec();
+                    result.ok = self.apply_write(args.command);
+                    if conditional {
+                        result.value = self.value(&key).cloned();
+                        self.cond_results.insert(args.client_id, (args.request_seq, result.ok, result.value.clone()));
+                    }
+                }
+            },
+            Err(_) => result.err = RespErr::ErrBadCommand,
+        }
+        self.notify_if_present(msg.index, result);
+    }
+
+    // returns false if the write has a condition which did not hold. writes
+    // which replace a value also drop its expiry, appends keep it.
+    fn apply_write(&mut self, command: Command) -> bool {
+        match command {
+            Command::Get { .. } | Command::Sweep => (),
+            Command::Compact { revision } => self.compact(revision),
+            Command::Txn { guards, success, failure } => return self.apply_txn(guards, success, failure).iter().all(|&held| held),
+            Command::Put { key, value } => {
+                self.expiry.remove(&key);
+                self.set(key, value);
+            },
+            Command::Append { key, value } => {
+                let mut current = self.value(&key).cloned().unwrap_or_default();
+                current.extend(value);
+                self.set(key, current);
+            },
+            Command::Delete { key } => {
+                self.expiry.remove(&key);
+                self.remove(&key, EventOp::Delete);
+            },
+            Command::CompareAndSwap { key, expected, new } => {
+                if self.value(&key) != Some(&expected) {
+                    return false;
+                }
+                self.expiry.remove(&key);
+                self.set(key, new);
+            },
+            Command::PutIfAbsent { key, value } => {
+                if self.value(&key).is_some() {
+                    return false;
+                }
+                self.set(key, value);
+            },
+            Command::DeleteIfEqual { key, expected } => {
+                if self.value(&key) != Some(&expected) {
+                    return false;
+                }
+                self.expiry.remove(&key);
+                self.remove(&key, EventOp::Delete);
+            },
+            Command::PutWithTtl { key, value, ttl } => {
+                self.expiry.insert(key.clone(), self.clock + ttl);
+                self.set(key, value);
+            },
+        }
+        true
+    }
+
+    // check every guard, then do success if they all held and failure if not.
+    // returns whether each guard held.
+    fn apply_txn(&mut self, guards: Vec<Guard>, success: Vec<TxnOp>, failure: Vec<TxnOp>) -> Vec<bool> {
+        let keys: Vec<Vec<u8>> = guards.iter().map(|g| g.key())
+            .chain(success.iter().chain(failure.iter()).map(|op| op.key()))
+            .map(|key| key.to_vec())
+            .collect();
+        for key in keys {
+            self.expire(&key);
+        }
+        let held: Vec<bool> = guards.iter().map(|guard| match guard {
+            Guard::Equals { key, value } => self.value(key) == Some(value),
+            Guard::Exists { key } => self.value(key).is_some(),
+            Guard::Absent { key } => self.value(key).is_none(),
+        }).collect();
+        let ops = if held.iter().all(|&h| h) { success } else { failure };
+        for op in ops {
+            self.apply_write(op.into());
+        }
+        held
+    }
+
+    // drop key if it expired by the log's clock.
+    fn expire(&mut self, key: &[u8]) {
+        if self.expiry.get(key).is_some_and(|&at| at <= self.clock) {
+            self.expiry.remove(key);
+            self.remove(key, EventOp::Expire);
+        }
+    }
+
+    // applied entries only change data through set and remove, so watchers
+    // hear of every change and every change gets a version.
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.history.push_back(WatchEvent{key: key.clone(), value: Some(value.clone()), op: EventOp::Put, index: self.last_applied});
+        self.add_version(key, Some(value));
+    }
+
+    fn remove(&mut self, key: &[u8], op: EventOp) {
+        if self.value(key).is_some() {
+            self.history.push_back(WatchEvent{key: key.to_vec(), value: None, op, index: self.last_applied});
+            self.add_version(key.to_vec(), None);
+        }
+    }
+
+    // a later write by the same entry, in a transaction, replaces the
+    // earlier one's version.
+    fn add_version(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
+        let revision = self.last_applied;
+        let versions = self.data.entry(key).or_default();
+        if versions.last().is_some_and(|v| v.revision == revision) {
+            versions.pop();
+        }
+        let version = match versions.last() {
+            _ if value.is_none() => 0,
+            Some(Version{version, value: Some(_), ..}) => version + 1,
+            _ => 1,
+        };
+        versions.push(Version{revision, version, value});
+    }
+
+    // drop every version older than the one each key had at revision, and
+    // keys deleted by then. reads at revision or later see no difference.
+    fn compact(&mut self, revision: usize) {
+        let revision = revision.min(self.last_applied);
+        if revision <= self.compacted {
+            return;
+        }
+        self.data.retain(|_, versions| {
+            let keep = versions.iter().rposition(|v| v.revision <= revision).unwrap_or(0);
+            versions.drain(..keep);
+            if versions[0].revision <= revision && versions[0].value.is_none() {
+                versions.remove(0);
+            }
+            !versions.is_empty()
+        });
+        self.compacted = revision;
+        let start = self.history.partition_point(|e| e.index < revision);
+        self.history.drain(..start);
+        self.history_start = self.history_start.max(revision);
+    }
+
+    // expired keys read as absent right away, but only leave the state once
+    // a leader logs a sweep, so every replica drops them at the same entry.
+    // versions older than max_revisions are compacted away the same way.
+    fn sweep(mu: Arc<Mutex<KVServer>>) {
+        loop {
+            thread::sleep(Duration::from_millis(SWEEP_INTERVAL));
+            let (rf, commands) = {
+                let kv = mu.lock().unwrap();
+                let now = now_ms();
+                let mut commands = Vec::new();
+                if kv.expiry.values().any(|&at| at <= now) {
+                    commands.push(Command::Sweep);
+                }
+                // a compaction is an entry too, one dropping just the last
+                // would leave an idle cluster compacting forever
+                let max = kv.max_revisions;
+                if kv.last_applied > kv.compacted + max + 1 {
+                    commands.push(Command::Compact { revision: kv.last_applied - max });
+                }
+                if commands.is_empty() {
+                    continue;
+                }
+                (kv.rf.clone(), commands)
+            };
+            if Raft::is_leader(rf.clone()) {
+                for command in commands {
+                    let args = ReqArgs { client_id: 0, request_seq: 0, command };
+                    Raft::start(rf.clone(), &args.encode(now_ms()));
                 }
             }
-        } else {
-            result.err = RespErr::ErrWrongLeader;
         }
+    }
+
+    // a configuration entry was committed, only the waiting admin call cares.
+    fn apply_config(&mut self, msg: &ApplyMsg) {
+        self.last_applied = msg.index;
+        let result = NotifyArgs{
+            term: msg.term,
+            value: None,
+            ok: false,
+            guards: Vec::new(),
+            err: RespErr::OK,
+        };
         self.notify_if_present(msg.index, result);
     }
 
+    // ask raft to compact its log once the persisted state is too big.
+    fn maybe_snapshot(&self) {
+        if let Some(max) = self.maxraftstate {
+            if self.persister.raft_state_size() >= max {
+                let state = (&self.data, self.compacted, &self.cache, &self.cond_results, &self.txn_results, &self.expiry, self.clock);
+                let snapshot = serialize(&(self.last_applied, state)).unwrap();
+                // a snapshot is already being taken, a later entry will retry
+                let _ = self.snapshot_ch.try_send((self.last_applied, snapshot));
+            }
+        }
+    }
+
+    fn restore_snapshot(&mut self, snapshot: &[u8]) {
+        if snapshot.is_empty() {
+            return;
+        }
+        let (last_applied, (data, compacted, cache, cond_results, txn_results, expiry, clock)) = deserialize(snapshot).unwrap();
+        self.last_applied = last_applied;
+        self.data = data;
+        self.compacted = compacted;
+        self.cache = cache;
+        self.cond_results = cond_results;
+        self.txn_results = txn_results;
+        self.expiry = expiry;
+        self.clock = clock;
+        self.history.clear();
+        self.history_start = last_applied + 1;
+    }
+
     fn run(mu: Arc<Mutex<KVServer>>, apply_ch: Receiver<ApplyMsg>) {
         loop {
             let msg = apply_ch.recv();
@@ -145,46 +640,51 @@ impl KVServer {
             match msg {
                 Ok(m) => {
                     let mut kv = mu.lock().unwrap();
+                    if m.index <= kv.last_applied {
+                        continue;   // already in our state, e.g. replayed after a restart
+                    }
                     if m.valid {
                         kv.apply(&m);
+                        kv.maybe_snapshot();
+                    } else if m.config.is_some() {
+                        kv.apply_config(&m);
+                        kv.maybe_snapshot();
+                    } else if !m.snapshot.is_empty() {
+                        kv.restore_snapshot(&m.snapshot);
+                    } else {
+                        kv.last_applied = m.index;  // no-op from a new leader
                     }
+                    kv.applied.notify_all();
                 },
                 Err(_) => continue,
             }
         }
     }
 
-    fn register_callback(
-        kv: &Arc<Mutex<KVServer>>,
-        mut reply_sender: Vec<SyncSender<(Vec<u8>, bool)>>,
-        mut req_recv: Vec<Receiver<Vec<u8>>>
-    ) {
+    // the rpc methods clerks call on us.
+    fn make_service(kv: &Arc<Mutex<KVServer>>) -> Service {
+        let mut svc = Service::new("KV");
         let kv1 = kv.clone();
-        let get_req = req_recv.remove(0);
-        let get_reply = reply_sender.remove(0);
-        thread::spawn(move || { //RequestVote
-            loop {
-                let args = get_req.recv().unwrap();
-
-                let req : ReqArgs = deserialize(&args[..]).unwrap();
-                let reply = Self::get(kv1.clone(), &req);
-                let reply = serialize(&reply).unwrap();
-                get_reply.send((reply, true)).unwrap();
-            }
-        });
-
+        svc.add_method("Get", move |args: ReqArgs| Self::get(kv1.clone(), &args));
+        let kv8 = kv.clone();
+        svc.add_method("GetAt", move |args: GetAtArgs| Self::get_at(kv8.clone(), &args));
         let kv2 = kv.clone();
-        let put_req = req_recv.remove(0);
-        let put_reply = reply_sender.remove(0);
-        thread::spawn(move || { //RequestVote
-            loop {
-                let args = put_req.recv().unwrap();
-
-                let req : ReqArgs = deserialize(&args[..]).unwrap();
-                let reply = Self::put_append(kv2.clone(), &req);
-                let reply = serialize(&reply).unwrap();
-                put_reply.send((reply, true)).unwrap();
-            }
-        });
+        svc.add_method("PutAppend", move |args: ReqArgs| Self::put_append(kv2.clone(), &args));
+        let kv4 = kv.clone();
+        svc.add_method("Cas", move |args: ReqArgs| Self::cas(kv4.clone(), &args));
+        let kv5 = kv.clone();
+        svc.add_method("Scan", move |args: ScanArgs| Self::scan(kv5.clone(), &args));
+        let kv6 = kv.clone();
+        svc.add_method("Txn", move |args: ReqArgs| Self::txn(kv6.clone(), &args));
+        let kv7 = kv.clone();
+        svc.add_method("Watch", move |args: WatchArgs| Self::watch(kv7.clone(), &args));
+        let kv3 = kv.clone();
+        svc.add_method("ChangeConfig", move |args: ConfigChangeArgs| Self::change_config(kv3.clone(), &args));
+        svc
     }
 }
+
+// ms since the unix epoch.
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
@@ -4,9 +4,13 @@ use std::env;
 use std::thread;
 use std::time::Duration;
 use kv_service::kv::server;
+use kv_service::raft::persister::Persister;
 use kv_service::raft::rpc::Client;
 use kv_service::kv::client;
 
+const MAX_RAFT_STATE: usize = 1 << 20;  // snapshot once the raft log reaches 1MB
+const MAX_REVISIONS: usize = 100000;   // keep old versions of keys for this many log entries
+
 fn main() {
 	let args: Vec<String> = env::args().collect();
 
@@ -21,7 +25,13 @@ fn main() {
 
     let mut clients = Vec::new();
     if cur_id != server_num as i32 {
-        server::KVServer::new(cur_id, &addrs);
+        let persister = Persister::new(&format!("data/{}", cur_id));
+        // "join" starts a server which waits to be added to a running cluster
+        if args.len() > 3 && args[3] == "join" {
+            server::KVServer::join(cur_id, &addrs, persister, Some(MAX_RAFT_STATE), Some(MAX_REVISIONS));
+        } else {
+            server::KVServer::new(cur_id, &addrs, persister, Some(MAX_RAFT_STATE), Some(MAX_REVISIONS));
+        }
     } else {
         // client
         for i in 0..server_num {
@@ -30,13 +40,13 @@ fn main() {
         let mut clerk = client::Clerk::new(&clients, 0);
 
         for i in 0..500 {
-            clerk.put(&String::from(format!("key {}",i)), &String::from(format!("value {}",i)));
-            let v = clerk.get(&String::from(format!("key {}",i)));
+            clerk.put(&format!("key {}",i), &format!("value {}",i));
+            let v = clerk.get(&format!("key {}",i));
             println!("get {}", v);
         }
 
         for i in (0..500).rev() {
-            let v = clerk.get(&String::from(format!("key {}",i)));
+            let v = clerk.get(&format!("key {}",i));
             println!("get {}", v);
         }
     }
@@ -0,0 +1,107 @@
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct Member {
+    pub id: i32,
+    pub addr: String,
+}
+
+// cluster configuration carried by configuration log entries.
+// while a change is in progress the cluster runs under joint consensus:
+// old_servers holds the previous voters and every decision needs a
+// majority of both sets.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Default)]
+pub struct Configuration {
+    pub servers: Vec<Member>,       // voters of the new (or only) configuration
+    pub old_servers: Vec<Member>,   // voters of the old configuration, empty unless joint
+}
+
+impl Configuration {
+    // the configuration a cluster boots with, server i listens on addrs[i].
+    pub fn from_addrs(addrs: &[String]) -> Configuration {
+        let servers = addrs.iter().enumerate()
+            .map(|(i, addr)| Member { id: i as i32, addr: addr.clone() })
+            .collect();
+        Configuration { servers, old_servers: Vec::new() }
+    }
+
+    pub fn is_joint(&self) -> bool {
+        !self.old_servers.is_empty()
+    }
+
+    // whether id is a voter in either half of the configuration.
+    pub fn contains(&self, id: i32) -> bool {
+        self.servers.iter().chain(self.old_servers.iter()).any(|m| m.id == id)
+    }
+
+    // all members of both halves, without duplicates.
+    pub fn members(&self) -> Vec<Member> {
+        let mut members: Vec<Member> = Vec::new();
+        for m in self.servers.iter().chain(self.old_servers.iter()) {
+            if !members.iter().any(|x| x.id == m.id) {
+                members.push(m.clone());
+            }
+        }
+        members
+    }
+
+    // whether the given voters form a majority, of both halves when joint.
+    pub fn has_quorum(&self, votes: &[i32]) -> bool {
+        let majority = |servers: &[Member]| {
+            let n = servers.iter().filter(|m| votes.contains(&m.id)).count();
+            n > servers.len() / 2
+        };
+        !self.servers.is_empty() && majority(&self.servers)
+            && (!self.is_joint() || majority(&self.old_servers))
+    }
+
+    // highest index replicated on a majority, match_index is indexed by server id.
+    pub fn quorum_index(&self, match_index: &[usize]) -> usize {
+        let majority = |servers: &[Member]| {
+            let mut matched: Vec<usize> = servers.iter()
+                .map(|m| match_index.get(m.id as usize).cloned().unwrap_or(0))
+                .collect();
+            matched.sort_unstable_by(|a, b| b.cmp(a));
+            matched.get(servers.len() / 2).cloned().unwrap_or(0)
+        };
+        let index = majority(&self.servers);
+        if self.is_joint() {
+            std::cmp::min(index, majority(&self.old_servers))
+        } else {
+            index
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(new: &[i32], old: &[i32]) -> Configuration {
+        let member = |id: &i32| Member { id: *id, addr: format!("127.0.0.1:{}", 9000 + id) };
+        Configuration {
+            servers: new.iter().map(member).collect(),
+            old_servers: old.iter().map(member).collect(),
+        }
+    }
+
+    #[test]
+    fn config_quorum_test() {
+        let c = config(&[0, 1, 2], &[]);
+        assert!(c.has_quorum(&[0, 2]));
+        assert!(!c.has_quorum(&[1]));
+        assert_eq!(c.quorum_index(&[5, 3, 1]), 3);
+
+        // growing 0,1,2 to 0,1,2,3,4 needs 3 of the new and 2 of the old
+        let c = config(&[0, 1, 2, 3, 4], &[0, 1, 2]);
+        assert!(c.is_joint());
+        assert_eq!(c.members().len(), 5);
+        assert!(!c.has_quorum(&[0, 3, 4]));
+        assert!(c.has_quorum(&[0, 1, 3]));
+        assert_eq!(c.quorum_index(&[9, 1, 1, 9, 9]), 1);
+        assert_eq!(c.quorum_index(&[9, 9, 1, 9, 1]), 9);
+
+        // a server waiting to join has no voters at all
+        let c = Configuration::default();
+        assert!(!c.has_quorum(&[0]));
+        assert_eq!(c.quorum_index(&[4]), 0);
+    }
+}
@@ -1,23 +1,29 @@
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, sync_channel, Receiver};
+use std::sync::mpsc::{channel, Sender, SyncSender};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use bincode::{deserialize, serialize};
+use bincode::serialize;
 use rand::Rng;
 
-use self::rpc::Client;
-use self::State::{Candidate, Follower, Leader};
+use self::config::Configuration;
+use self::node::{Body, Message, Node};
+use self::persister::Persister;
+use self::rpc::{ANetwork, Client, RpcError, Service};
 
+pub mod config;
+pub mod node;
+pub mod persister;
 pub mod rpc;
+#[cfg(test)]
+mod sim;
 mod util;
 
-const HEARBEAT_INTERVAL: u64 = 50;
-//const ELECTION_TIMEOUT:u64 = 1000;
-const MIN_TIMEOUT: u64 = 200;
-const MAX_TIMEOUT: u64 = 400;
-
-const CALLBACK_NUMS : u32 = 4;
+const TICK_INTERVAL: u64 = 10;  // ms of real time per tick of the node's clock
+const READ_INDEX_TIMEOUT: u64 = 1000;   // give up confirming leadership for a read after this many ms
+const RPC_TIMEOUT: u64 = 500;   // give up sending a message to another server after this many ms
+const SNAPSHOT_RPC_TIMEOUT: u64 = 5000;
+const TRANSFER_TIMEOUT: u64 = 2000;     // ms to wait for the leader after we stepped down for a transfer
 
 pub enum State {
     Follower,
@@ -29,21 +35,56 @@ pub enum State {
 pub struct LogEntry {
     pub term: u64,
     pub command: Vec<u8>,
+    pub config: Option<Configuration>,  // set for configuration change entries
 }
 
+// valid is false for a committed configuration entry (config is set), for the
+// no-op a new leader commits, and for a snapshot installed from the leader, in
+// that case index and term are the last entry it includes.
 pub struct ApplyMsg {
     pub valid: bool,
     pub index: usize,
     pub term: u64,
     pub command: Vec<u8>,
+    pub snapshot: Vec<u8>,
+    pub config: Option<Configuration>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(PartialEq, Debug)]
+pub enum ConfigError {
+    NotLeader,
+    InProgress,             // another configuration change has not finished yet
+    AlreadyMember,
+    NotMember,
+    EmptyConfiguration,     // would remove the last server
+}
+
+#[derive(PartialEq, Debug)]
+pub enum TransferError {
+    NotLeader,
+    NotVoter,               // the target is not in the configuration
+    TimedOut,               // the target did not take over in time, we still lead
+    SteppedDown,            // we lost leadership in the same term
+    OtherLeader,            // a server other than the target leads the newer term
+    NoLeader,               // nobody was heard leading the newer term in time
+}
+
+#[derive(PartialEq, Debug)]
+pub enum ReadError {
+    NotLeader,
+    TimedOut,               // no majority confirmed our leadership in time
+}
+
+// term, vote_for, last_included_index, last_included_term, snapshot configuration and log
+type HardState = (u64, i32, usize, u64, Configuration, Vec<LogEntry>);
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub struct RequestVoteArgs {
     pub term: u64,
     pub candidate_id: i32,
     pub last_log_index: usize,
     pub last_log_term: u64,
+    pub leader_transfer: bool,  // the leader asked the candidate to take over
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
@@ -60,6 +101,7 @@ pub struct AppendEntriesArgs {
     pub prev_log_term: u64,
     pub entries: Vec<LogEntry>,
     pub leader_commit: usize,
+    pub round: u64,     // heartbeat round of the leader, echoed in the reply
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
@@ -67,555 +109,583 @@ pub struct AppendEntriesReply {
     pub term: u64,
     pub success: bool,
     pub first_index: usize,  // first index in conflict term
+    pub match_index: usize,  // last index known to match the leader's log, on success
+    pub round: u64,
 }
 
-pub struct Raft {
-    peers: Vec<Client>,     // id of all peers
-    pub me: i32,        // this peer's id, index of peers vec
-    pub state: State,   // current state of this peer
-    apply_ch: SyncSender<ApplyMsg>,
-
-    pub current_term: u64,  // latest term server has seen (initialized to 0 on first boot, increases monotonically)
-    vote_for: i32,          // candidateId that received vote in current term (or -1 if none)
-    commit_index: usize,      // index of highest log entry known to be committed (initialized to 0, increases monotonically)
-    log: Vec<LogEntry>,     // log entries (first index is 1)
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct InstallSnapshotArgs {
+    pub term: u64,
+    pub leader_id: i32,
+    pub last_included_index: usize,
+    pub last_included_term: u64,
+    pub config: Configuration,  // configuration as of last_included_index
+    pub data: Vec<u8>,
+    pub round: u64,
+}
 
-    pub next_index: Vec<usize>, // for each server, index of the next log entry to send to that server (initialized to leader last log index + 1)
-    pub match_index: Vec<usize>, // for each server, index of highest log entry known to be replicated on server (initialized to 0, increases monotonically)
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct InstallSnapshotReply {
+    pub term: u64,
+    pub last_included_index: usize,
+    pub round: u64,
+}
 
-    election_timer: SyncSender<()>,
+// sent by a leader handing over leadership, the receiver campaigns at once.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct TimeoutNowArgs {
+    pub term: u64,
+    pub leader_id: i32,
+}
 
-    pub voted_cnt: i32, // voted count during a election
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct TimeoutNowReply {
+    pub term: u64,
+}
 
-    reply_sender : Vec<SyncSender<(Vec<u8>, bool)>>,
+// a raft node running for real: a thread ticks the node in step with the
+// wall clock, messages travel over the rpc network and the hard state goes
+// to the persister before anything the node produced leaves this server.
+pub struct Raft {
+    node: Node,
+    peers: Vec<Client>,     // by server id, to send messages with
+    applier: Sender<ApplyMsg>,  // to the thread handing committed entries to the service
+    persister: Persister,   // holds current_term, vote_for and log across restarts
 }
 
 impl Raft {
-    // create a new raft node.
+    // create a new raft node, all of addr form the initial cluster.
+    // the persisted state is reloaded before this node takes part in any rpc.
     pub fn new(
         id: i32,
-        addr : &Vec<String>,
+        addr : &[String],
+        persister: Persister,
         apply_ch: &SyncSender<ApplyMsg>,
-    ) -> (Arc<Mutex<Raft>>, Client, Vec<SyncSender<(Vec<u8>, bool)>>, Vec<Receiver<Vec<u8>>>) {
-        let (peers, mut reply_sendv, mut req_recvv) = Self::create_server(addr, id);
-        let put_reply = reply_sendv.pop().unwrap();
-        let get_reply = reply_sendv.pop().unwrap();
+    ) -> (Arc<Mutex<Raft>>, Client, ANetwork) {
+        Self::make(id, addr, persister, apply_ch, Configuration::from_addrs(addr))
+    }
+
+    // create a raft node which joins a running cluster. it starts without any
+    // configuration and stays passive until the leader adds it with add_server.
+    pub fn join(
+        id: i32,
+        addr : &[String],
+        persister: Persister,
+        apply_ch: &SyncSender<ApplyMsg>,
+    ) -> (Arc<Mutex<Raft>>, Client, ANetwork) {
+        Self::make(id, addr, persister, apply_ch, Configuration::default())
+    }
 
-        let put_req = req_recvv.pop().unwrap();
-        let get_req = req_recvv.pop().unwrap();
+    fn make(
+        id: i32,
+        addr : &[String],
+        persister: Persister,
+        apply_ch: &SyncSender<ApplyMsg>,
+        config: Configuration,
+    ) -> (Arc<Mutex<Raft>>, Client, ANetwork) {
+        let (peers, network) = Self::create_server(addr, id);
         let client = peers[id as usize].clone();
 
-//        let (ns, nr) = mpsc::sync_channel(1);
-//        let (ms, mr) = mpsc::sync_channel(1);
-        let (ts, tr) = mpsc::sync_channel(1);
+        let state = persister.read_raft_state();
+        let node = Node::new(id, config, &state, persister.read_snapshot(), rand::thread_rng().gen());
+
+        // the service may be waiting for our lock while its apply_ch is full,
+        // so entries queue up here instead of blocking handle_ready
+        let (applier, committed) = channel();
+        let apply_ch = apply_ch.clone();
+        thread::spawn(move || {
+            for msg in committed {
+                if apply_ch.send(msg).is_err() {
+                    return;
+                }
+            }
+        });
+
         let mut r = Raft {
-            peers: peers,
-            me: id,
-            state: Follower,
-            apply_ch: apply_ch.clone(),
-            current_term: 0,
-            vote_for: -1,
-            commit_index: 0,
-            log: vec![LogEntry {
-                term: 0,
-                command: Vec::new(),
-            }],
-            next_index: Vec::new(),
-            match_index: Vec::new(),
-            voted_cnt: 0,
-            election_timer: ts,
-            reply_sender : reply_sendv,
+            node,
+            peers,
+            applier,
+            persister,
         };
-        r.next_index.resize(r.peers.len(),0);
-        r.match_index.resize(r.peers.len(),0);
+        r.connect();
         let ret = Arc::new(Mutex::new(r));
 
-        Self::register_callback(&ret, req_recvv);
+        network.add_service(Self::make_service(&ret));
 
         let arc_r = ret.clone();
-        // election daemon
-        thread::spawn(move || { Self::tick_election(tr, arc_r) });
-        (ret, client, vec![get_reply, put_reply], vec![get_req, put_req])
+        thread::spawn(move || { Self::tick(arc_r) });
+        (ret, client, network)
     }
 
-    // start to execute a command.
-    // if this is not leader, return false immediately
-    // return values: command index in the log, current term, is_leader
-    pub fn start(r: Arc<Mutex<Raft>>, command: &Vec<u8>) -> (usize, u64, bool) {
+    // start to agree on command. returns its index in the log, the current
+    // term and whether we are the leader, if not nothing was appended.
+    pub fn start(r: Arc<Mutex<Raft>>, command: &[u8]) -> (usize, u64, bool) {
         let mut rf = r.lock().unwrap();
-//        println!("{} starts",rf.me);
-        let (index, term, mut is_leader) = (rf.log.len(), rf.current_term, false);
-
-        if let Leader = rf.state {
-            is_leader = true;
-            let (me,current_term) = (rf.me as usize,rf.current_term);
-            rf.match_index[me] = index;
-            rf.log.push(LogEntry{term:current_term, command:command.clone()});
-//            println!("{} is leader, return", rf.me);
-        }
-        (index,term,is_leader)
+        let ret = rf.node.propose(command);
+        rf.handle_ready();
+        ret
     }
 
-    // implement AppendEntries RPC.
-    pub fn append_entries(r: &Arc<Mutex<Raft>>, args: &mut AppendEntriesArgs) -> AppendEntriesReply {
-        let mut rf = r.lock().unwrap();
-        // println!("run append_entries in id {}", rf.me);
-
-        let mut reply = AppendEntriesReply {
-            success: false, // success only if leader is valid and prev entry matched
-            term: rf.current_term,
-            first_index: args.prev_log_index+1,
-        };
-
-        if args.term < rf.current_term { // expired leader
-            return reply;
-        }
-        rf.election_timer.send(()).unwrap();   // valid leader, reset election timeout
+    // get current state of Raft.
+    pub fn get_state(r: Arc<Mutex<Raft>>) -> (u64, bool) {
+        let raft = r.lock().unwrap();
+        println!("get {} state",raft.node.me);
+        (raft.node.term(), raft.node.is_leader())
+    }
 
-        if args.term > rf.current_term{
-            rf.current_term = args.term;
-            reply.term = rf.current_term;
-        }
+    // whether we are the leader, quietly for callers which ask all the time.
+    pub fn is_leader(r: Arc<Mutex<Raft>>) -> bool {
+        r.lock().unwrap().node.is_leader()
+    }
 
-        rf.state = Follower;
+    // with pre vote on, a server only becomes candidate once a majority would vote for it.
+    pub fn set_pre_vote(r: Arc<Mutex<Raft>>, enabled: bool) {
+        r.lock().unwrap().node.set_pre_vote(enabled);
+    }
 
-        let mut last = 0; // last entry matched
-        let prev_entry_match = args.prev_log_index<rf.log.len() && rf.log[args.prev_log_index].term == args.prev_log_term;
+    // with lease reads on, read_index skips the heartbeat round while the leader's lease holds.
+    pub fn set_lease_read(r: Arc<Mutex<Raft>>, enabled: bool) {
+        r.lock().unwrap().node.set_lease_read(enabled);
+    }
 
-        if prev_entry_match {
-            last = args.prev_log_index;
-            reply.success = true;
-            if args.entries.len()>0 {
-//                println!("{} get entry from {}",rf.me,args.leader_id);
-                // delete conflict entries
-                last+=args.entries.len();
-                rf.log.truncate(args.prev_log_index+1);
-                rf.log.append(&mut args.entries);
+    // hand leadership over to target, which must be a voter.
+    // returns once target leads a newer term, or with an error once the node
+    // gave up on the transfer or lost leadership some other way.
+    pub fn transfer_leadership(r: Arc<Mutex<Raft>>, target: i32) -> Result<(), TransferError> {
+        let term = {
+            let mut rf = r.lock().unwrap();
+            let res = rf.node.transfer_leadership(target);
+            rf.handle_ready();
+            res?;
+            if target == rf.node.me {
+                return Ok(());
             }
-        } else {
-            // to find first index in conflict term
-            let mut index;
-            if args.prev_log_index < rf.log.len() {
-                // search the first entry in conflict term
-                index = args.prev_log_index;
-                let term = rf.log[index].term;
-                while term == rf.log[index-1].term && index > 1 {
-                    index -= 1
+            rf.node.term()
+        };
+        let mut stepped_down: Option<Instant> = None;
+        loop {
+            {
+                let rf = r.lock().unwrap();
+                if rf.node.is_leader() {
+                    if rf.node.transferee() != target {
+                        return Err(TransferError::TimedOut);
+                    }
+                } else if rf.node.term() == term {
+                    return Err(TransferError::SteppedDown);
+                } else if let Some(leader) = rf.node.leader() {
+                    return if leader == target { Ok(()) } else { Err(TransferError::OtherLeader) };
+                } else {
+                    // we stepped down for a newer term, wait to hear who leads it
+                    let since = *stepped_down.get_or_insert_with(Instant::now);
+                    if since.elapsed() >= Duration::from_millis(TRANSFER_TIMEOUT) {
+                        return Err(TransferError::NoLeader);
+                    }
                 }
-            } else {
-                index = rf.log.len();
             }
-
-            reply.first_index = index;
+            thread::sleep(Duration::from_millis(10));
         }
+    }
 
-        // try commit
-        if args.leader_commit > rf.commit_index && prev_entry_match {
-            let commit_index = std::cmp::min(args.leader_commit, last);
-            if rf.commit_index<commit_index {
-                let r1 = r.clone();
-                let leader_commit = args.leader_commit;
-                thread::spawn(move || { Self::commit_to_index(r1, std::cmp::min(leader_commit, last)) });
+    // ReadIndex: return a commit index a linearizable read may be served at
+    // once the service has applied it.
+    pub fn read_index(r: Arc<Mutex<Raft>>) -> Result<usize, ReadError> {
+        let start = Instant::now();
+        let read = {
+            let mut rf = r.lock().unwrap();
+            let res = rf.node.read_index();
+            rf.handle_ready();
+            res?
+        };
+        loop {
+            if let Some(index) = r.lock().unwrap().node.read_ready(read)? {
+                return Ok(index);
             }
-//            Self::commit_to_index(r1,std::cmp::min(args.leader_commit, last));
+            if start.elapsed() >= Duration::from_millis(READ_INDEX_TIMEOUT) {
+                return Err(ReadError::TimedOut);
+            }
+            thread::sleep(Duration::from_millis(5));
         }
+    }
 
-        reply
+    // the service saved everything up to and including index in snapshot, drop those entries.
+    pub fn snapshot(r: Arc<Mutex<Raft>>, index: usize, snapshot: Vec<u8>) {
+        let mut rf = r.lock().unwrap();
+        rf.node.compact(index, snapshot);
+        rf.handle_ready();
     }
 
-    // implement RequestVote RPC.
-    pub fn request_vote(r: &Arc<Mutex<Raft>>, args: &RequestVoteArgs) -> RequestVoteReply {
+    // start adding server id at addr, returns index and term of the joint configuration entry.
+    pub fn add_server(r: Arc<Mutex<Raft>>, id: i32, addr: String) -> Result<(usize, u64), ConfigError> {
         let mut rf = r.lock().unwrap();
-        // println!("run request_vote in id {}", rf.me);
-        let mut reply = RequestVoteReply { term: rf.current_term, vote_granted: false };
-        if args.term < rf.current_term {
-            // reject because candidate expired
-            println!("{} refuse for term to {}", rf.me, args.candidate_id);
-            return reply;
-        }
+        let res = rf.node.add_server(id, addr);
+        rf.handle_ready();
+        res
+    }
 
-        // candidate's log entry inspect
-        let last_index = rf.last_index();
-        let up_to_date = if rf.log[last_index].term < args.last_log_term {
-            true
-        } else if rf.log[last_index].term < args.last_log_term {
-            false
-        } else {
-            args.last_log_index >= last_index
-        };
+    // start removing server id, returns index and term of the joint configuration entry.
+    pub fn remove_server(r: Arc<Mutex<Raft>>, id: i32) -> Result<(usize, u64), ConfigError> {
+        let mut rf = r.lock().unwrap();
+        let res = rf.node.remove_server(id);
+        rf.handle_ready();
+        res
+    }
 
-        if !up_to_date {
-            println!("{} refuse for log entry not up to date to {}", rf.me, args.candidate_id);
-            return reply;
+    // drive the node's clock from the wall clock. a tick that came late, e.g.
+    // because the lock was busy, is caught up at once so the node never sees
+    // less time pass than really did.
+    fn tick(r: Arc<Mutex<Raft>>) {
+        let start = Instant::now();
+        let mut ticks = 0;
+        loop {
+            thread::sleep(Duration::from_millis(TICK_INTERVAL));
+            let due = start.elapsed().as_millis() as u64 / TICK_INTERVAL;
+            let mut rf = r.lock().unwrap();
+            while ticks < due {
+                rf.node.tick();
+                ticks += 1;
+            }
+            rf.handle_ready();
         }
+    }
 
-        //if candidate's term is greater, grant
-        if args.term > rf.current_term {
-            rf.vote_for = -1;
-            rf.current_term = args.term;
-            reply.term = rf.current_term;
-        }
+    // implement the Step RPC, every message between servers arrives here.
+    fn step(r: &Arc<Mutex<Raft>>, msg: Message) {
+        let mut rf = r.lock().unwrap();
+        rf.node.step(msg);
+        rf.handle_ready();
+    }
 
-        if rf.vote_for == -1 {
-            rf.election_timer.send(()).unwrap();
-            rf.state = Follower;
-            reply.vote_granted = true;
-            println!("grant server {} to {} in term {}", rf.me, args.candidate_id, args.term);
-            rf.vote_for = args.candidate_id;
+    // carry out what the node asked for, called with the lock held. the hard
+    // state is saved before any message goes out, committed entries go to the
+    // service in log order.
+    fn handle_ready(&mut self) {
+        let ready = self.node.ready();
+        match (ready.state, ready.snapshot) {
+            (Some(state), Some(snapshot)) => self.persister.save_state_and_snapshot(&state, &snapshot),
+            (Some(state), None) => self.persister.save_raft_state(&state),
+            _ => {},
+        }
+        if !ready.messages.is_empty() {
+            self.connect();
+        }
+        for msg in ready.messages {
+            self.send(msg);
         }
-        if reply.vote_granted == false {
-            println!("{} refuse {} because already voted for {}\n",rf.me, args.candidate_id, rf.vote_for);
+        for msg in ready.apply {
+            self.applier.send(msg).unwrap();
         }
-        reply
     }
 
-    // get current state of Raft.
-    pub fn get_state(r: Arc<Mutex<Raft>>) -> (u64, bool) {
-        let raft = r.lock().unwrap();
-        println!("get {} state",raft.me);
-        let term = raft.current_term;
-        let is_leader = match raft.state {
-            Leader => true,
-            _ => false,
+    // send msg on its own thread, a lost message is resent by the node when it matters.
+    fn send(&self, msg: Message) {
+        let to = msg.to;
+        let client = match self.peers.get(to as usize) {
+            Some(client) if !client.server_addr.is_empty() => client.clone(),
+            _ => {
+                println!("{} has no address for server {}", self.node.me, to);
+                return;
+            },
         };
-        (term, is_leader)
+        let timeout = match msg.body {
+            Body::InstallSnapshot(_) => SNAPSHOT_RPC_TIMEOUT,
+            _ => RPC_TIMEOUT,
+        };
+        thread::spawn(move || {
+            if let Err(err) = Self::send_message(&client, &msg, Duration::from_millis(timeout)) {
+                println!("failed to send message to {}, error:{:?}", to, err);
+            }
+        });
     }
 
-    // leader election.
-    fn campaign(r: Arc<Mutex<Raft>>) {
-        let mut rf = r.lock().unwrap();
-        rf.voted_cnt = 0;
-        rf.vote_for = rf.me;
-        rf.state = Candidate;
-        rf.current_term += 1;
-        let last_index = rf.last_index();
-        let last_term = rf.log[last_index].term;
-//        let args = RequestVoteArgs { term: rf.current_term, candidate_id: rf.me, last_log_index: last_index, last_log_term: last_term };
-
-        // send request to every peer
-        for i in 0..rf.peers.len() {
-            if i as i32 == rf.me {
-                continue;
+    // call Step RPC of one peer.
+    fn send_message(client: &Client, msg: &Message, timeout: Duration) -> Result<(), RpcError> {
+        let req = serialize(msg).unwrap();
+        client.call_with_timeout(String::from("Raft.Step"), req, timeout)?;
+        Ok(())
+    }
+
+    // make sure we can reach every member of the latest configuration.
+    fn connect(&mut self) {
+        let me = self.node.me;
+        for m in self.node.config().members() {
+            let id = m.id as usize;
+            if id >= self.peers.len() {
+                self.peers.resize(id+1, Client::new());
+            }
+            if self.peers[id].server_addr != m.addr {
+                self.peers[id] = Client {
+                    end_name: rpc::end_name(me, m.id),
+                    server_addr: m.addr.clone(),
+                };
             }
-            let r1 = r.clone();
-            let client = rf.peers[i].clone();
-            let args = RequestVoteArgs { term: rf.current_term, candidate_id: rf.me, last_log_index: last_index, last_log_term: last_term };
-            // send requests
-            thread::spawn(move || {
-                match Self::send_request_vote(&client, args) {
-                    // got reply
-                    Ok(reply) => {
-                        let mut rf1 = r1.lock().unwrap();
-//                        println!("{} get reply from {}", rf1.me, i);
-                        if let Candidate = rf1.state {
-                            //got voted
-                            if reply.vote_granted && reply.term==rf1.current_term {
-                                rf1.voted_cnt += 1;
-                                println!("{} get voted {} times", rf1.me,rf1.voted_cnt);
-                                // win
-                                if rf1.voted_cnt as usize == rf1.peers.len() / 2 {
-                                    rf1.state = Leader;
-                                    println!("{} is leader of term {}",rf1.me,rf1.current_term);
-                                    // initiate leader state
-                                    for i in 0..rf1.peers.len() {
-                                        rf1.match_index[i] = 0;
-                                        rf1.next_index[i] = rf1.log.len();
-                                    }
-                                    let me = rf1.me as usize;
-                                    rf1.match_index[me] = rf1.last_index();
-                                    // tick heart beat
-                                    let r1 = r1.clone();
-                                    thread::spawn(move || {
-                                        Self::tick_heartbeat(r1);
-                                    });
-                                }
-                            } else {
-                                println!("{} didnt get voted from {}", rf1.me, i);
-                                if reply.term > rf1.current_term {
-                                    rf1.state = Follower;
-                                    rf1.election_timer.send(()).unwrap();  // reset timer
-                                    rf1.current_term = reply.term;
-                                }
-                            }
-                        }
-                    }
-                    Err(err) => {
-                         println!("no reply while send vote request to {}, error:{:?}", i, err);
-                    }
-                }
-            });
         }
     }
 
-    // call AppendEntries RPC of one peer.
-    fn send_append_entries(client:&Client, args: AppendEntriesArgs) -> Result<AppendEntriesReply, &'static str> {
-        let req = serialize(&args).unwrap();
-        let (reply, success) = client.call(String::from("Raft.AppendEntries"),req);
-        if success {
-            let reply: AppendEntriesReply = deserialize(&reply).unwrap();
-            return Ok(reply);
-        }
-        Err("get append entries rpc reply error")
+    // the rpc methods other servers call on us.
+    fn make_service(r: &Arc<Mutex<Raft>>) -> Service {
+        let mut svc = Service::new("Raft");
+        let rr = r.clone();
+        svc.add_method("Step", move |msg: Message| Self::step(&rr, msg));
+        svc
     }
 
-    // call RequestVote RPC of one peer.
-    fn send_request_vote(client: &Client, args: RequestVoteArgs) -> Result<RequestVoteReply, &'static str> {
-//        let reply = RequestVoteReply{term:0, vote_granted:false};
-        let req = serialize(&args).unwrap();
-        let (reply, success) = client.call(String::from("Raft.RequestVote"), req);
-        if success {
-            let reply: RequestVoteReply = deserialize(&reply).unwrap();
-            return Ok(reply);
+    fn create_server(addrs : &[String], cur_id : i32) -> (Vec<Client>, ANetwork) {
+        let rn1 = rpc::make_network(addrs[cur_id as usize].clone());
+
+        println!("creating server {}", cur_id);
+
+        let mut clients = Vec::new();
+        for (j, addr) in addrs.iter().enumerate() {
+            // if cur_id as usize == j {
+            //     clients.push(Client::new());
+            // } else {
+                let client = rpc::make_end(&rn1, rpc::end_name(cur_id, j as i32), addr.clone());
+                clients.push(client);
+            // }
         }
-        Err("get request vote rpc reply error")
+
+        (clients, rn1)
     }
+}
 
-    // send heartbeat to followers within a given time interval.
-    // only call by leader.
-    // heartbeats include append_entries rpc
-    fn tick_heartbeat(r: Arc<Mutex<Raft>>) {
-        loop {
-            {
-//                 println!("broadcast before lock");
-                let rf = r.lock().unwrap();
-                println!("leader {} broadcast", rf.me);
-                if let Leader = rf.state {
-                    rf.election_timer.send(()).unwrap();  //reset timer so leader won't start another election
-                    // broadcast
-                    for i in 0..rf.peers.len() {
-                        if i == rf.me as usize {
-                            continue;
-                        }
-
-                        // avoid out of index range
-                        let pre_index = std::cmp::min(rf.next_index[i]-1,rf.last_index());
-                        let pre_term = rf.log[pre_index].term;
-
-                        let mut args = AppendEntriesArgs{
-                            leader_id:rf.me,
-                            term:rf.current_term,
-                            entries:vec![],
-                            leader_commit:rf.commit_index,
-                            prev_log_term:pre_term,
-                            prev_log_index:pre_index,
-                        };
-
-                        // append multiple entries
-                        let mut next = rf.next_index[i];
-                        let mut cnt = 0;
-                        while next < rf.log.len() && cnt<10 {
-//                            println!("leader {} in term {} append entires at index {} for {}",rf.me,rf.current_term, next, i);
-                            args.entries.push(rf.log[next].clone());
-                            next += 1;
-                            cnt+=1;
-                        }
-
-                        // start send append rpc to each server
-                        let r1 = r.clone();
-                        let client = rf.peers[i].clone();
-                        thread::spawn(move||{
-                            let num_entries = args.entries.len();
-                            match Self::send_append_entries(&client, args) {
-                                Ok(reply) => {
-                                    let mut rf1 = r1.lock().unwrap();
-                                    if let Leader = rf1.state {
-                                        if reply.success {
-                                            // update index state and try to commit
-                                            rf1.match_index[i] = pre_index+num_entries;
-                                            rf1.next_index[i] += num_entries;
-//                                            println!("next entry for {} is {}",i,rf1.next_index[i]);
-                                            let r2 = r1.clone();
-                                            // try to commit new appended entries
-                                            thread::spawn(move||{Self::leader_commit(r2)});
-                                        } else {
-                                            if reply.term > rf1.current_term { // leader expired
-                                                rf1.state = Follower;
-                                                rf1.election_timer.send(()).unwrap();
-                                                rf1.current_term = reply.term;
-                                            } else { // update next entry according to reply
-                                                rf1.next_index[i] = reply.first_index;
-                                            }
-                                        }
-                                    }
-                                }
-                                Err(err) => {
-                                    println!("no reply while send append request to {}, error:{:?}", i, err);
-                                }
-                            }
-                        });
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::collections::HashMap;
+    use std::sync::mpsc::{sync_channel, Receiver};
+    use std::thread;
+    use super::*;
+
+    type Applied = Arc<Mutex<Vec<HashMap<usize, Vec<u8>>>>>;
+    type Servers = (Vec<Arc<Mutex<Raft>>>, Vec<ANetwork>, Vec<Receiver<ApplyMsg>>);  // rafts, networks and apply_chs
+
+    // start n servers on in-process addresses, applied[i] records what server i applied by index.
+    fn make_cluster(name: &str, n: usize) -> (Vec<Arc<Mutex<Raft>>>, Vec<ANetwork>, Applied) {
+        let (rafts, nets, apply_chs) = make_servers(name, n, n, 100);
+        let applied: Applied = Arc::new(Mutex::new(vec![HashMap::new(); n]));
+        for (i, rx) in apply_chs.into_iter().enumerate() {
+            let applied1 = applied.clone();
+            thread::spawn(move || {
+                for m in rx {
+                    if m.valid {
+                        applied1.lock().unwrap()[i].insert(m.index, m.command);
                     }
-                } else {
-                    return;
                 }
-            } // unlock during sleep
-            thread::sleep(Duration::from_millis(HEARBEAT_INTERVAL));
+            });
         }
+        (rafts, nets, applied)
     }
 
-    // start election after timeout.
-    fn tick_election(receiver: Receiver<()>, r: Arc<Mutex<Raft>>) {
-        loop {
-            match receiver.recv_timeout(Self::random_timeout(MIN_TIMEOUT, MAX_TIMEOUT)) {
-                Ok(_) => continue,
-                Err(RecvTimeoutError::Timeout) => {
-                    {
-                        let rf = r.lock().unwrap();
-                        println!("{} timeout, start election!",rf.me);
-                    }
-                    let r1 = r.clone();
-                    thread::spawn(move || { Self::campaign(r1) });
-                },
-                Err(_) => {
-                    println!("election timer error");
-                },
-            };
+    // start the first up servers of an n server cluster from empty data
+    // directories, each with an apply_ch of apply_cap messages left to the caller.
+    fn make_servers(name: &str, n: usize, up: usize, apply_cap: usize) -> Servers {
+        let addrs = mem_addrs(name, n);
+        let mut rafts = Vec::new();
+        let mut nets = Vec::new();
+        let mut apply_chs = Vec::new();
+        for i in 0..up {
+            let dir = env::temp_dir().join(format!("kv-service-{}-{}", name, i));
+            let _ = fs::remove_dir_all(&dir);
+            let persister = Persister::new(dir.to_str().unwrap());
+            let (sx, rx) = sync_channel(apply_cap);
+            let (raft, _, network) = Raft::new(i as i32, &addrs, persister, &sx);
+            rafts.push(raft);
+            nets.push(network);
+            apply_chs.push(rx);
         }
+        (rafts, nets, apply_chs)
     }
 
-    // leader try to commit
-    fn leader_commit(r: Arc<Mutex<Raft>>) {
-        let rf = r.lock().unwrap();
-        match rf.state {
-            Leader => {},
-            _ => return,    // not leader, return
-        };
-        let mut match_state = rf.match_index.clone();
-        match_state.sort();
-
-        let majority = match_state[match_state.len()/2];  //match index of majority
+    // in-process addresses, tests never touch real ports.
+    fn mem_addrs(name: &str, n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("mem:{}-{}", name, i)).collect()
+    }
 
-        // only commit current term's entry
-        if rf.log[majority].term == rf.current_term && rf.commit_index<majority {
-            let r1 = r.clone();
-            thread::spawn(move||{
-                Self::commit_to_index(r1,majority);
-            });
-        }
+    fn leaders(rafts: &[Arc<Mutex<Raft>>]) -> Vec<usize> {
+        (0..rafts.len()).filter(|&i| Raft::get_state(rafts[i].clone()).1).collect()
     }
 
-    // commit index and all indices preceding index
-    fn commit_to_index(r: Arc<Mutex<Raft>>,index: usize) {
-//        println!("commit lock");
-        let mut rf = r.lock().unwrap();
-//        println!("{} commit start\n",rf.me);
-        if rf.commit_index < index {
-            for i in rf.commit_index+1..index+1 {
-                if i<rf.log.len() {
-                    rf.commit_index = i;
-                    let msg = ApplyMsg{
-                        command:rf.log[i].command.clone(),
-                        valid:true,
-                        index:i,
-                        term:rf.log[i].term,
-                    };
-                     rf.apply_ch.send(msg).unwrap();
+    // get cmd committed and applied by every server, retrying through leader changes.
+    fn one(rafts: &[Arc<Mutex<Raft>>], applied: &Applied, cmd: &[u8]) -> usize {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while Instant::now() < deadline {
+            for r in rafts {
+                let (index, _, ok) = Raft::start(r.clone(), cmd);
+                if !ok {
+                    continue;
                 }
+                let wait = Instant::now() + Duration::from_secs(2);
+                while Instant::now() < wait {
+                    let applied = applied.lock().unwrap();
+                    let got: Vec<_> = applied.iter().filter_map(|a| a.get(&index)).collect();
+                    for c in got.iter() {
+                        assert_eq!(got[0], *c, "servers applied different commands at {}", index);
+                    }
+                    if got.len() == rafts.len() && got[0].as_slice() == cmd {
+                        return index;
+                    }
+                    drop(applied);
+                    thread::sleep(Duration::from_millis(20));
+                }
+                break;
             }
+            thread::sleep(Duration::from_millis(50));
         }
+        panic!("{:?} never committed", cmd);
     }
 
-    fn last_index(&self) -> usize {
-        self.log.len() - 1
+    #[test]
+    fn raft_test() {
+        let (rafts, _, applied) = make_cluster("raft-test", 5);
+        thread::sleep(Duration::from_secs(2));
+        let leader = leaders(&rafts);
+        assert_eq!(leader.len(), 1);
+
+        // a stable cluster keeps its leader and term
+        let (term, _) = Raft::get_state(rafts[leader[0]].clone());
+        thread::sleep(Duration::from_secs(1));
+        assert_eq!(leaders(&rafts), leader);
+        assert_eq!(Raft::get_state(rafts[leader[0]].clone()).0, term);
+
+        let first = one(&rafts, &applied, &[1]);
+        assert_eq!(one(&rafts, &applied, &[2]), first + 1);
     }
 
-    fn random_timeout(min: u64, max: u64) -> Duration {
-        let timeout = rand::thread_rng().gen_range(min, max);
-        Duration::from_millis(timeout)
+    #[test]
+    fn transfer_leadership_test() {
+        let (rafts, _, applied) = make_cluster("transfer-test", 3);
+        one(&rafts, &applied, &[1]);
+        let leader = leaders(&rafts)[0];
+        let target = (leader + 1) % 3;
+        assert_eq!(Raft::transfer_leadership(rafts[leader].clone(), target as i32), Ok(()));
+        thread::sleep(Duration::from_millis(500));
+        assert!(Raft::get_state(rafts[target].clone()).1);
+        assert!(!Raft::get_state(rafts[leader].clone()).1);
+
+        // only a leader can hand over leadership
+        assert_eq!(Raft::transfer_leadership(rafts[leader].clone(), target as i32), Err(TransferError::NotLeader));
     }
 
+    #[test]
+    fn transfer_lost_leadership_test() {
+        let (rafts, nets, applied) = make_cluster("transfer-lost-test", 3);
+        one(&rafts, &applied, &[1]);
+        let leader = leaders(&rafts)[0];
+        let target = (leader + 1) % 3;
+
+        // cut off, the leader steps down by check quorum before target ever hears of the transfer
+        rpc::partition(&nets, &[vec![leader as i32]]);
+        assert_eq!(Raft::transfer_leadership(rafts[leader].clone(), target as i32), Err(TransferError::SteppedDown));
+        assert!(!Raft::get_state(rafts[leader].clone()).1);
+    }
 
-    fn register_callback(r: &Arc<Mutex<Raft>>,  mut req_receiver : Vec<Receiver<Vec<u8>>>) {
-        let rr = r.clone();
-        let req_receiver0 = req_receiver.remove(0);
-        thread::spawn(move || { //RequestVote
-            loop {
-                let args = req_receiver0.recv().unwrap();
-                
-                let req : RequestVoteArgs = deserialize(&args[..]).unwrap();
-                let reply = Self::request_vote(&rr, &req);
-//                let vote_granted = reply.vote_granted;
-                let reply = serialize(&reply).unwrap();
-
-                let r1 = rr.lock().unwrap();
-                r1.reply_sender[0].send((reply, true)).unwrap();
-            }
+    #[test]
+    fn slow_apply_test() {
+        let (rafts, _, apply_chs) = make_servers("slow-apply-test", 1, 1, 1);
+        let (raft, rx) = (rafts[0].clone(), &apply_chs[0]);
+        thread::sleep(Duration::from_secs(1));
+        assert!(Raft::get_state(raft.clone()).1);
+
+        // nobody takes from apply_ch for a while, raft keeps going regardless
+        let (done_sx, done_rx) = sync_channel(1);
+        let raft1 = raft.clone();
+        thread::spawn(move || {
+            let indexes: Vec<usize> = (0..10u8).map(|i| Raft::start(raft1.clone(), &[i]).0).collect();
+            done_sx.send(indexes).unwrap();
         });
-        let rr = r.clone();
-        let req_receiver1 = req_receiver.remove(0);
-        thread::spawn(move || { //AppendEntries
-            loop {
-                let args = req_receiver1.recv().unwrap();
-                
-                let mut req : AppendEntriesArgs = deserialize(&args[..]).unwrap();
-                let reply = Self::append_entries(&rr, &mut req);
-                let reply = serialize(&reply).unwrap();
-
-                let r1 = rr.lock().unwrap();
-                r1.reply_sender[1].send((reply, true)).unwrap();
+        let indexes = done_rx.recv_timeout(Duration::from_secs(2)).expect("start blocked on a full apply_ch");
+        let mut applied = Vec::new();
+        while applied.len() < indexes.len() {
+            let m = rx.recv_timeout(Duration::from_secs(2)).unwrap();
+            if m.valid {
+                applied.push(m.index);
             }
-        });
+        }
+        assert_eq!(applied, indexes);
     }
 
-    fn create_server(addrs : &Vec<String>, cur_id : i32) -> (Vec<Client>, Vec<SyncSender<(Vec<u8>, bool)>>, Vec<Receiver<Vec<u8>>>) {
-        let mut req_sendv = Vec::new();
-        let mut reply_sendv = Vec::new();
-        let mut req_recvv = Vec::new();
-        let mut reply_recvv = Vec::new();
-        
-        for _i in 0..CALLBACK_NUMS {
-            let (req_send, req_recv) = sync_channel(1);
-            let (reply_send, reply_recv) = sync_channel(1);
-
-            req_sendv.push(req_send);
-            reply_sendv.push(reply_send);
-            req_recvv.push(req_recv);
-            reply_recvv.push(reply_recv);
-        }
+    #[test]
+    fn check_quorum_test() {
+        let (rafts, nets, applied) = make_cluster("check-quorum-test", 3);
+        one(&rafts, &applied, &[1]);
+        let leader = leaders(&rafts)[0];
+
+        // cut off from the others, the leader's lease runs out
+        rpc::partition(&nets, &[vec![leader as i32]]);
+        thread::sleep(Duration::from_millis(200));
+        assert!(Raft::get_state(rafts[leader].clone()).1);
+        // no lease and no heartbeat round confirm the leadership
+        assert_eq!(Raft::read_index(rafts[leader].clone()), Err(ReadError::NotLeader));
+
+        thread::sleep(Duration::from_millis(2 * node::MAX_ELECTION_TICKS * TICK_INTERVAL));
+        assert!(!Raft::get_state(rafts[leader].clone()).1);
+    }
 
-        let rn1 = rpc::make_network(addrs[cur_id as usize].clone(), req_sendv, reply_recvv);
+    #[test]
+    fn pre_vote_test() {
+        // only server 0 of three is up, it can never win an election
+        let (rafts, _, _apply_chs) = make_servers("pre-vote-test", 3, 1, 100);
+        let raft = rafts[0].clone();
+
+        // the pre vote rounds fail, so the term never moves
+        thread::sleep(Duration::from_secs(2));
+        assert_eq!(Raft::get_state(raft.clone()), (0, false));
+
+        // without them every election timeout starts a new term
+        Raft::set_pre_vote(raft.clone(), false);
+        thread::sleep(Duration::from_secs(2));
+        let (term, is_leader) = Raft::get_state(raft.clone());
+        assert!(term > 1);
+        assert!(!is_leader);
+    }
 
-        println!("creating server {}", cur_id);
+    #[test]
+    fn partition_test() {
+        let (rafts, nets, applied) = make_cluster("partition-test", 3);
+        one(&rafts, &applied, &[1]);
+        let old_leader = leaders(&rafts)[0] as i32;
+        let (old_term, _) = Raft::get_state(rafts[old_leader as usize].clone());
+
+        // the other two elect a new leader, the old one steps down for lack of a quorum
+        let rest: Vec<i32> = (0..3).filter(|&i| i != old_leader).collect();
+        rpc::partition(&nets, &[vec![old_leader], rest.clone()]);
+        thread::sleep(Duration::from_secs(2));
+        let new_leaders = leaders(&rafts);
+        assert_eq!(new_leaders.len(), 1);
+        assert!(rest.contains(&(new_leaders[0] as i32)));
+        assert!(Raft::get_state(rafts[new_leaders[0]].clone()).0 > old_term);
+
+        // the majority keeps committing without the old leader
+        let (index, _, ok) = Raft::start(rafts[new_leaders[0]].clone(), &[2]);
+        assert!(ok);
         thread::sleep(Duration::from_secs(1));
-
-        let mut clients = Vec::new();
-        for j in 0..addrs.len() {
-            // if cur_id as usize == j {
-            //     clients.push(Client::new());
-            // } else {
-                let client = rpc::make_end(&rn1, format!("client{}to{}", cur_id, j), addrs[j].clone());
-                clients.push(client);
-            // }
+        for &i in rest.iter() {
+            assert_eq!(applied.lock().unwrap()[i as usize].get(&index), Some(&vec![2]));
         }
+        assert!(!applied.lock().unwrap()[old_leader as usize].contains_key(&index));
 
-        (clients, reply_sendv, req_recvv)
+        // after healing everybody catches up under a single leader
+        rpc::partition(&nets, &[vec![0, 1, 2]]);
+        one(&rafts, &applied, &[3]);
+        assert_eq!(leaders(&rafts).len(), 1);
+        assert_eq!(applied.lock().unwrap()[old_leader as usize].get(&index), Some(&vec![2]));
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use std::thread;
-    use super::*;
 
     #[test]
-    fn raft_test() {
-        let server_num = 3;
-        let mut base_port = 8810;
-        let mut addrs = Vec::new();
-        for i in (0..server_num) {
-            addrs.push(format!("127.0.0.1:{}", base_port));
-            base_port += 1;
+    fn unreliable_test() {
+        let (rafts, nets, applied) = make_cluster("unreliable-test", 3);
+        for net in nets.iter() {
+            net.set_reliable(false);
+            net.set_long_reordering(true);
         }
-        let aaddrs = Arc::new(addrs);
-
-        for i in (0..server_num) {
-            let aaddrs1 = aaddrs.clone();
-            thread::spawn(move || {
-                let (sx, rx) = sync_channel(1);
-                let raft = Raft::new(i, &aaddrs1, &sx);
-                thread::sleep(Duration::from_secs(60));
-            });
+        let mut last = 0;
+        for cmd in 0..5u8 {
+            let index = one(&rafts, &applied, &[cmd]);
+            assert!(index > last);
+            last = index;
         }
-
-        thread::sleep(Duration::from_secs(60));
+        // a leader that never lost its place is only called by the other two
+        assert!(nets.iter().filter(|net| net.count() > 0).count() >= 2);
     }
 }
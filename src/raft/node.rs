@@ -0,0 +1,1077 @@
+use std::collections::VecDeque;
+use std::mem;
+
+use bincode::{deserialize, serialize};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use super::config::{Configuration, Member};
+use super::State::{self, Candidate, Follower, Leader};
+use super::{ApplyMsg, ConfigError, HardState, LogEntry, ReadError, TransferError};
+use super::{AppendEntriesArgs, AppendEntriesReply, InstallSnapshotArgs, InstallSnapshotReply};
+use super::{RequestVoteArgs, RequestVoteReply, TimeoutNowArgs, TimeoutNowReply};
+
+// all timeouts are in ticks of the logical clock, see Node::tick.
+pub const HEARTBEAT_TICKS: u64 = 5;
+pub const MIN_ELECTION_TICKS: u64 = 20;
+pub const MAX_ELECTION_TICKS: u64 = 40;
+const TRANSFER_TICKS: u64 = 100;   // give up a leadership transfer after this many ticks
+const LEASE_TICKS: u64 = 15;       // leader lease, shorter than MIN_ELECTION_TICKS to allow for clock drift
+const MAX_ENTRIES: usize = 10;     // entries per AppendEntries
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Message {
+    pub from: i32,
+    pub to: i32,
+    pub body: Body,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub enum Body {
+    RequestVote(RequestVoteArgs),
+    RequestVoteReply(RequestVoteReply),
+    PreVote(RequestVoteArgs),
+    PreVoteReply(RequestVoteReply),
+    AppendEntries(AppendEntriesArgs),
+    AppendEntriesReply(AppendEntriesReply),
+    InstallSnapshot(InstallSnapshotArgs),
+    InstallSnapshotReply(InstallSnapshotReply),
+    TimeoutNow(TimeoutNowArgs),
+    TimeoutNowReply(TimeoutNowReply),
+}
+
+// everything a node produced since the last call to ready. state (and
+// snapshot, when set) must be on disk before any of messages is sent.
+pub struct Ready {
+    pub state: Option<Vec<u8>>,     // encoded hard state to persist
+    pub snapshot: Option<Vec<u8>>,  // new snapshot to persist together with state
+    pub messages: Vec<Message>,
+    pub apply: Vec<ApplyMsg>,       // committed entries and installed snapshots, in order
+}
+
+// a read waits until the leader of term heard back from a majority for the
+// heartbeat round sent after the read arrived. round 0 when the lease covers it.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ReadIndex {
+    term: u64,
+    round: u64,
+}
+
+// the raft state machine. it has no threads, clock or network of its own:
+// time only moves on tick(), messages come in through step() and whatever it
+// wants done goes out through ready(), so a run is fully determined by the
+// order of those calls and the seed.
+pub struct Node {
+    pub me: i32,        // this peer's id
+    pub state: State,   // current state of this peer
+
+    current_term: u64,  // latest term server has seen (initialized to 0 on first boot, increases monotonically)
+    vote_for: i32,          // candidateId that received vote in current term (or -1 if none)
+    commit_index: usize,      // index of highest log entry known to be committed (initialized to 0, increases monotonically)
+    log: Vec<LogEntry>,     // log entries, log[0] stands for last_included_index
+    last_included_index: usize, // last index covered by the snapshot
+    last_included_term: u64,    // term of last_included_index
+    snapshot: Vec<u8>,          // the service's snapshot up to last_included_index
+
+    config: Configuration,          // latest configuration in the log, committed or not
+    config_index: usize,            // index of the entry config comes from
+    snapshot_config: Configuration, // configuration as of last_included_index
+
+    peers: Vec<i32>,            // servers we replicate to
+    next_index: Vec<usize>,     // for each server, index of the next log entry to send to that server (initialized to leader last log index + 1)
+    match_index: Vec<usize>,    // for each server, index of highest log entry known to be replicated on server (initialized to 0, increases monotonically)
+    ack_round: Vec<u64>,        // for each server, the latest heartbeat round it answered in our term, 0 for none
+    ack_tick: Vec<u64>,         // for each server, when we sent ack_round
+    round: u64,                 // heartbeat rounds sent so far, replies echo it back
+    rounds: VecDeque<(u64, u64)>,   // recent rounds and the tick they were sent at
+
+    now: u64,                   // ticks since start
+    election_elapsed: u64,
+    election_timeout: u64,      // randomized between MIN_ELECTION_TICKS and MAX_ELECTION_TICKS
+    heartbeat_elapsed: u64,
+    leader_contact: u64,        // last tick we heard from a valid leader, or led ourselves
+
+    votes: Vec<i32>,            // servers which voted for us during a election, including ourselves
+    pre_vote: bool,             // run a PreVote round before bumping the term
+    pre_votes: Vec<i32>,        // servers which would vote for us in the next term, including ourselves
+
+    transferee: i32,            // server we are handing leadership to (or -1 if none)
+    leader: Option<(u64, i32)>, // term and id of the last leader we heard from, or were
+    transfer_deadline: u64,     // abort the transfer if it has not happened by then
+
+    lease_read: bool,           // serve reads from the leader lease without a heartbeat round
+    elected_at: u64,            // when we last became leader
+    lease_revoked: bool,        // we sent TimeoutNow in this term, another leader may show up any time
+
+    rng: StdRng,
+    msgs: Vec<Message>,
+    apply: Vec<ApplyMsg>,
+    dirty: bool,                // hard state changed since the last ready
+    snapshot_dirty: bool,       // and so did the snapshot
+}
+
+impl Node {
+    // state and snapshot are what ready handed out before a restart, empty on
+    // first boot. config is the configuration to start with if the log has none.
+    pub fn new(id: i32, config: Configuration, state: &[u8], snapshot: Vec<u8>, seed: u64) -> Node {
+        let mut n = Node {
+            me: id,
+            state: Follower,
+            current_term: 0,
+            vote_for: -1,
+            commit_index: 0,
+            log: vec![LogEntry {
+                term: 0,
+                command: Vec::new(),
+                config: None,
+            }],
+            last_included_index: 0,
+            last_included_term: 0,
+            snapshot,
+            config: config.clone(),
+            config_index: 0,
+            snapshot_config: config,
+            peers: Vec::new(),
+            next_index: Vec::new(),
+            match_index: Vec::new(),
+            ack_round: Vec::new(),
+            ack_tick: Vec::new(),
+            round: 0,
+            rounds: VecDeque::new(),
+            now: 0,
+            election_elapsed: 0,
+            election_timeout: MAX_ELECTION_TICKS,
+            heartbeat_elapsed: 0,
+            leader_contact: 0,
+            votes: Vec::new(),
+            pre_vote: true,
+            pre_votes: Vec::new(),
+            transferee: -1,
+            leader: None,
+            transfer_deadline: 0,
+            lease_read: true,
+            elected_at: 0,
+            lease_revoked: false,
+            rng: StdRng::seed_from_u64(seed),
+            msgs: Vec::new(),
+            apply: Vec::new(),
+            dirty: false,
+            snapshot_dirty: false,
+        };
+        n.connect(id);
+        n.restore(state);
+        n.commit_index = n.last_included_index;  // the snapshot only holds committed entries
+        n.reload_config();
+        n.reset_election_timer();
+        n
+    }
+
+    // advance the logical clock by one tick: followers and candidates start an
+    // election once their timeout ran out, leaders send heartbeats.
+    pub fn tick(&mut self) {
+        self.now += 1;
+        if let Leader = self.state {
+            self.heartbeat_elapsed += 1;
+            if self.heartbeat_elapsed >= HEARTBEAT_TICKS {
+                self.heartbeat();
+            }
+            return;
+        }
+        self.election_elapsed += 1;
+        if self.election_elapsed >= self.election_timeout {
+            self.reset_election_timer();
+            if self.config.contains(self.me) {  // not a voter (yet, or any more) otherwise
+                println!("{} timeout, start election!", self.me);
+                self.pre_campaign();
+            }
+        }
+    }
+
+    // handle a message from another server.
+    pub fn step(&mut self, msg: Message) {
+        let from = msg.from;
+        match msg.body {
+            Body::RequestVote(args) => self.request_vote(from, &args),
+            Body::RequestVoteReply(reply) => self.request_vote_reply(from, &reply),
+            Body::PreVote(args) => self.handle_pre_vote(from, &args),
+            Body::PreVoteReply(reply) => self.pre_vote_reply(from, &reply),
+            Body::AppendEntries(mut args) => self.append_entries(from, &mut args),
+            Body::AppendEntriesReply(reply) => self.append_entries_reply(from, &reply),
+            Body::InstallSnapshot(args) => self.install_snapshot(from, args),
+            Body::InstallSnapshotReply(reply) => self.install_snapshot_reply(from, &reply),
+            Body::TimeoutNow(args) => self.timeout_now(from, &args),
+            Body::TimeoutNowReply(reply) => {
+                if reply.term > self.current_term {
+                    self.step_down(reply.term);
+                }
+            },
+        }
+    }
+
+    // take what the node produced since the last call.
+    pub fn ready(&mut self) -> Ready {
+        let state = if self.dirty || self.snapshot_dirty { Some(self.encode_state()) } else { None };
+        let snapshot = if self.snapshot_dirty { Some(self.snapshot.clone()) } else { None };
+        self.dirty = false;
+        self.snapshot_dirty = false;
+        Ready {
+            state,
+            snapshot,
+            messages: mem::take(&mut self.msgs),
+            apply: mem::take(&mut self.apply),
+        }
+    }
+
+    pub fn term(&self) -> u64 {
+        self.current_term
+    }
+
+    pub fn is_leader(&self) -> bool {
+        matches!(self.state, Leader)
+    }
+
+    pub fn commit_index(&self) -> usize {
+        self.commit_index
+    }
+
+    pub fn config(&self) -> &Configuration {
+        &self.config
+    }
+
+    pub fn transferee(&self) -> i32 {
+        self.transferee
+    }
+
+    // the leader of the current term, None until we heard from it.
+    pub fn leader(&self) -> Option<i32> {
+        self.leader.filter(|&(term, _)| term == self.current_term).map(|(_, id)| id)
+    }
+
+    // turn the PreVote round on or off, on by default.
+    pub fn set_pre_vote(&mut self, enabled: bool) {
+        self.pre_vote = enabled;
+    }
+
+    // turn lease reads on or off, on by default. without them every read
+    // confirms our leadership with a heartbeat round.
+    pub fn set_lease_read(&mut self, enabled: bool) {
+        self.lease_read = enabled;
+    }
+
+    // append a command to the log if we are the leader.
+    // return values: command index in the log, current term, is_leader
+    pub fn propose(&mut self, command: &[u8]) -> (usize, u64, bool) {
+        let (index, term, mut is_leader) = (self.last_index()+1, self.current_term, false);
+
+        // stop taking proposals while handing over leadership
+        if matches!(self.state, Leader) && self.transferee == -1 {
+            is_leader = true;
+            let me = self.me as usize;
+            self.match_index[me] = index;
+            self.log.push(LogEntry{term, command:command.to_vec(), config:None});
+            self.persist();
+        }
+        (index, term, is_leader)
+    }
+
+    // implement AppendEntries RPC.
+    fn append_entries(&mut self, from: i32, args: &mut AppendEntriesArgs) {
+        let mut reply = AppendEntriesReply {
+            success: false, // success only if leader is valid and prev entry matched
+            term: self.current_term,
+            first_index: args.prev_log_index+1,
+            match_index: 0,
+            round: args.round,
+        };
+
+        if args.term < self.current_term { // expired leader
+            self.send(from, Body::AppendEntriesReply(reply));
+            return;
+        }
+        self.reset_election_timer();   // valid leader, reset election timeout
+        self.leader_contact = self.now;
+
+        if args.term > self.current_term {
+            self.current_term = args.term;
+            self.vote_for = -1;
+            reply.term = self.current_term;
+        }
+
+        self.state = Follower;
+        self.leader = Some((args.term, args.leader_id));
+
+        if args.prev_log_index < self.last_included_index {
+            // already covered by our snapshot, ask for the entries after it
+            reply.first_index = self.last_included_index+1;
+            self.persist();
+            self.send(from, Body::AppendEntriesReply(reply));
+            return;
+        }
+
+        let mut last = 0; // last entry matched
+        let prev_entry_match = args.prev_log_index <= self.last_index() && self.term_at(args.prev_log_index) == args.prev_log_term;
+
+        if prev_entry_match {
+            last = args.prev_log_index + args.entries.len();
+            reply.success = true;
+            reply.match_index = last;
+            if !args.entries.is_empty() {
+                // skip the entries we already have, a stale request arriving
+                // late must not cut off the ones a newer request appended
+                let mut index = args.prev_log_index+1;
+                let mut entries = args.entries.drain(..).peekable();
+                while index <= self.last_index() && entries.peek().map(|e| e.term) == Some(self.term_at(index)) {
+                    entries.next();
+                    index += 1;
+                }
+                let entries: Vec<LogEntry> = entries.collect();
+                // delete conflict entries
+                if !entries.is_empty() {
+                    let offset = self.last_included_index;
+                    let config_changed = self.config_index >= index
+                        || entries.iter().any(|e| e.config.is_some());
+                    self.log.truncate(index-offset);
+                    self.log.extend(entries);
+                    if config_changed {
+                        self.reload_config();
+                    }
+                }
+            }
+        } else {
+            // to find first index in conflict term
+            let mut index;
+            if args.prev_log_index <= self.last_index() {
+                // search the first entry in conflict term
+                index = args.prev_log_index;
+                let term = self.term_at(index);
+                while index > self.last_included_index+1 && term == self.term_at(index-1) {
+                    index -= 1
+                }
+            } else {
+                index = self.last_index()+1;
+            }
+
+            reply.first_index = index;
+        }
+        self.persist();   // must be durable before the leader sees success
+
+        // try commit
+        if args.leader_commit > self.commit_index && prev_entry_match {
+            self.commit_to(std::cmp::min(args.leader_commit, last));
+        }
+
+        self.send(from, Body::AppendEntriesReply(reply));
+    }
+
+    fn append_entries_reply(&mut self, i: i32, reply: &AppendEntriesReply) {
+        if reply.term > self.current_term { // leader expired
+            self.step_down(reply.term);
+            return;
+        }
+        if !matches!(self.state, Leader) || reply.term != self.current_term {
+            return;
+        }
+        self.ack(i, reply.round);     // i still follows us
+        let i = i as usize;
+        if reply.success {
+            // update index state and try to commit
+            if self.match_index[i] < reply.match_index {
+                self.match_index[i] = reply.match_index;
+            }
+            if self.next_index[i] <= reply.match_index {
+                self.next_index[i] = reply.match_index+1;
+            }
+            if self.transferee == i as i32 && self.match_index[i] == self.last_index() {
+                self.notify_transferee(i as i32);
+            }
+            self.leader_commit();
+        } else { // update next entry according to reply
+            self.next_index[i] = reply.first_index;
+        }
+    }
+
+    // implement RequestVote RPC.
+    fn request_vote(&mut self, from: i32, args: &RequestVoteArgs) {
+        let mut reply = RequestVoteReply { term: self.current_term, vote_granted: false };
+        if args.term < self.current_term {
+            // reject because candidate expired
+            println!("{} refuse for term to {}", self.me, args.candidate_id);
+            self.send(from, Body::RequestVoteReply(reply));
+            return;
+        }
+        // a leader may still hold its lease, don't even step into the new term
+        if !args.leader_transfer && self.now - self.leader_contact < MIN_ELECTION_TICKS {
+            println!("{} refuse {}, leader still alive", self.me, args.candidate_id);
+            self.send(from, Body::RequestVoteReply(reply));
+            return;
+        }
+
+        // candidate's log entry inspect
+        let up_to_date = self.log_up_to_date(args.last_log_index, args.last_log_term);
+
+        //if candidate's term is greater, step into its term even if we won't vote for it
+        if args.term > self.current_term {
+            self.vote_for = -1;
+            self.current_term = args.term;
+            self.state = Follower;
+            reply.term = self.current_term;
+        }
+
+        if !up_to_date {
+            println!("{} refuse for log entry not up to date to {}", self.me, args.candidate_id);
+            self.persist();
+            self.send(from, Body::RequestVoteReply(reply));
+            return;
+        }
+
+        if self.vote_for == -1 {
+            self.reset_election_timer();
+            self.state = Follower;
+            reply.vote_granted = true;
+            println!("grant server {} to {} in term {}", self.me, args.candidate_id, args.term);
+            self.vote_for = args.candidate_id;
+        }
+        self.persist();   // never grant a vote we could forget after a crash
+        if !reply.vote_granted {
+            println!("{} refuse {} because already voted for {}", self.me, args.candidate_id, self.vote_for);
+        }
+        self.send(from, Body::RequestVoteReply(reply));
+    }
+
+    fn request_vote_reply(&mut self, i: i32, reply: &RequestVoteReply) {
+        if let Candidate = self.state {
+            //got voted
+            if reply.vote_granted && reply.term == self.current_term {
+                if !self.votes.contains(&i) {
+                    self.votes.push(i);
+                }
+                println!("{} get voted {} times", self.me, self.votes.len()-1);
+                // win
+                if self.config.has_quorum(&self.votes) {
+                    self.become_leader();
+                }
+            } else {
+                println!("{} didnt get voted from {}", self.me, i);
+                if reply.term > self.current_term {
+                    self.step_down(reply.term);
+                }
+            }
+        }
+    }
+
+    // implement PreVote RPC. args.term is the term the candidate would campaign
+    // in, nothing changes here: we only tell it whether it would get our vote.
+    // a granted vote carries that term so the candidate can tell rounds apart.
+    fn handle_pre_vote(&mut self, from: i32, args: &RequestVoteArgs) {
+        let mut reply = RequestVoteReply { term: self.current_term, vote_granted: false };
+        if args.term <= self.current_term {
+            self.send(from, Body::PreVoteReply(reply));
+            return;
+        }
+        // a leader is still around, the candidate is the one cut off
+        if self.now - self.leader_contact < MIN_ELECTION_TICKS {
+            println!("{} refuse pre vote to {}, leader still alive", self.me, args.candidate_id);
+            self.send(from, Body::PreVoteReply(reply));
+            return;
+        }
+        if self.log_up_to_date(args.last_log_index, args.last_log_term) {
+            reply.vote_granted = true;
+            reply.term = args.term;
+        }
+        self.send(from, Body::PreVoteReply(reply));
+    }
+
+    fn pre_vote_reply(&mut self, i: i32, reply: &RequestVoteReply) {
+        if !reply.vote_granted {
+            if reply.term > self.current_term {
+                self.step_down(reply.term);
+            }
+            return;
+        }
+        // the round is over once our term moved or we won
+        if reply.term != self.current_term+1 || matches!(self.state, Leader) {
+            return;
+        }
+        if !self.pre_votes.contains(&i) {
+            self.pre_votes.push(i);
+            if self.config.has_quorum(&self.pre_votes) {
+                println!("{} get enough pre votes for term {}", self.me, reply.term);
+                self.start_election(false);
+            }
+        }
+    }
+
+    // whether a log ending at last_log_index in last_log_term is at least as up to date as ours.
+    fn log_up_to_date(&self, last_log_index: usize, last_log_term: u64) -> bool {
+        let last_index = self.last_index();
+        let last_term = self.term_at(last_index);
+        if last_term != last_log_term {
+            return last_log_term > last_term;
+        }
+        last_log_index >= last_index
+    }
+
+    // ask the voters whether they would elect us in the next term before
+    // starting a real election, so a server cut off from the cluster does not
+    // keep raising its term and depose a healthy leader once it is back.
+    fn pre_campaign(&mut self) {
+        if !self.pre_vote {
+            self.start_election(false);
+            return;
+        }
+        self.pre_votes = vec![self.me];
+        if self.config.has_quorum(&self.pre_votes) {
+            self.start_election(false);
+            return;
+        }
+        let last_index = self.last_index();
+        let args = RequestVoteArgs {
+            term: self.current_term+1,
+            candidate_id: self.me,
+            last_log_index: last_index,
+            last_log_term: self.term_at(last_index),
+            leader_transfer: false,
+        };
+        for m in self.config.members() {
+            if m.id != self.me {
+                self.send(m.id, Body::PreVote(args.clone()));
+            }
+        }
+    }
+
+    // become candidate of the next term and request votes.
+    // leader_transfer makes voters ignore that they still hear from the leader.
+    fn start_election(&mut self, leader_transfer: bool) {
+        self.votes = vec![self.me];
+        self.vote_for = self.me;
+        self.state = Candidate;
+        self.current_term += 1;
+        self.persist();
+        if self.config.has_quorum(&self.votes) {  // we are the only voter
+            self.become_leader();
+            return;
+        }
+        let last_index = self.last_index();
+        let args = RequestVoteArgs {
+            term: self.current_term,
+            candidate_id: self.me,
+            last_log_index: last_index,
+            last_log_term: self.term_at(last_index),
+            leader_transfer,
+        };
+        // send request to every voter
+        for m in self.config.members() {
+            if m.id != self.me {
+                self.send(m.id, Body::RequestVote(args.clone()));
+            }
+        }
+    }
+
+    // initiate leader state and send the first heartbeats.
+    fn become_leader(&mut self) {
+        self.state = Leader;
+        self.leader = Some((self.current_term, self.me));
+        self.transferee = -1;
+        self.elected_at = self.now;
+        self.lease_revoked = false;
+        println!("{} is leader of term {}", self.me, self.current_term);
+        for i in 0..self.next_index.len() {
+            self.match_index[i] = 0;
+            self.next_index[i] = self.last_index()+1;
+            self.ack_round[i] = 0;
+        }
+        self.rounds.clear();
+        // commit an empty entry of our own term, which also tells us how far
+        // the previous leaders' entries are committed
+        let term = self.current_term;
+        self.log.push(LogEntry { term, command: Vec::new(), config: None });
+        self.persist();
+        let me = self.me as usize;
+        self.match_index[me] = self.last_index();
+        // the previous leader committed a joint configuration but did not get
+        // to propose the new one, finish the change for it
+        if self.config.is_joint() && self.config_index <= self.commit_index {
+            let servers = self.config.servers.clone();
+            self.propose_config(Configuration { servers, old_servers: Vec::new() });
+        }
+        self.heartbeat();
+    }
+
+    // a newer term showed up, follow whoever leads it.
+    fn step_down(&mut self, term: u64) {
+        self.state = Follower;
+        self.reset_election_timer();
+        self.current_term = term;
+        self.vote_for = -1;
+        self.persist();
+    }
+
+    fn reset_election_timer(&mut self) {
+        self.election_elapsed = 0;
+        self.election_timeout = self.rng.gen_range(MIN_ELECTION_TICKS, MAX_ELECTION_TICKS);
+    }
+
+    // every HEARTBEAT_TICKS while we lead.
+    fn heartbeat(&mut self) {
+        self.heartbeat_elapsed = 0;
+        if self.transferee != -1 && self.now >= self.transfer_deadline {
+            println!("{} abort leadership transfer to {}", self.me, self.transferee);
+            self.transferee = -1;
+        }
+        // check quorum: a majority stopped answering, they have most
+        // likely moved on without us
+        if self.now - self.elected_at >= MAX_ELECTION_TICKS && !self.acked_since(self.now.saturating_sub(MAX_ELECTION_TICKS)) {
+            println!("{} lost contact with the majority, step down", self.me);
+            self.state = Follower;
+            self.leader = None;
+            self.reset_election_timer();
+            return;
+        }
+        self.leader_contact = self.now;
+        self.broadcast();
+    }
+
+    // send one round of AppendEntries to every peer.
+    fn broadcast(&mut self) {
+        self.round += 1;
+        self.rounds.push_back((self.round, self.now));
+        while self.rounds.front().is_some_and(|&(_, tick)| tick + MAX_ELECTION_TICKS < self.now) {
+            self.rounds.pop_front();
+        }
+        for id in self.peers.clone() {
+            let i = id as usize;
+
+            // follower is behind our snapshot, send the snapshot instead
+            if self.next_index[i] <= self.last_included_index {
+                self.replicate_snapshot(id);
+                continue;
+            }
+
+            // avoid out of index range
+            let pre_index = std::cmp::min(self.next_index[i]-1, self.last_index());
+            let mut args = AppendEntriesArgs {
+                leader_id: self.me,
+                term: self.current_term,
+                entries: vec![],
+                leader_commit: self.commit_index,
+                prev_log_term: self.term_at(pre_index),
+                prev_log_index: pre_index,
+                round: self.round,
+            };
+
+            // append multiple entries
+            let mut next = pre_index+1;
+            while next <= self.last_index() && args.entries.len() < MAX_ENTRIES {
+                args.entries.push(self.entry(next).clone());
+                next += 1;
+            }
+            self.send(id, Body::AppendEntries(args));
+        }
+        // a leader without other voters commits on its own
+        self.leader_commit();
+    }
+
+    // send our snapshot to server i which lags behind the compacted log.
+    fn replicate_snapshot(&mut self, i: i32) {
+        let args = InstallSnapshotArgs {
+            term: self.current_term,
+            leader_id: self.me,
+            last_included_index: self.last_included_index,
+            last_included_term: self.last_included_term,
+            config: self.snapshot_config.clone(),
+            data: self.snapshot.clone(),
+            round: self.round,
+        };
+        self.send(i, Body::InstallSnapshot(args));
+    }
+
+    fn install_snapshot_reply(&mut self, i: i32, reply: &InstallSnapshotReply) {
+        if reply.term > self.current_term {   // leader expired
+            self.step_down(reply.term);
+            return;
+        }
+        if !matches!(self.state, Leader) || reply.term != self.current_term {
+            return;
+        }
+        self.ack(i, reply.round);
+        let i = i as usize;
+        if self.match_index[i] < reply.last_included_index {
+            self.match_index[i] = reply.last_included_index;
+        }
+        self.next_index[i] = self.match_index[i]+1;
+    }
+
+    // i answered heartbeat round in our term.
+    fn ack(&mut self, i: i32, round: u64) {
+        let i = i as usize;
+        if round <= self.ack_round[i] {
+            return;
+        }
+        self.ack_round[i] = round;
+        if let Some(&(_, tick)) = self.rounds.iter().find(|&&(r, _)| r == round) {
+            self.ack_tick[i] = tick;
+        }
+    }
+
+    // hand leadership over to target, which must be a voter. proposals are
+    // refused until target has won an election or the transfer is aborted
+    // after TRANSFER_TICKS.
+    pub fn transfer_leadership(&mut self, target: i32) -> Result<(), TransferError> {
+        if let Leader = self.state {} else {
+            return Err(TransferError::NotLeader);
+        }
+        if target == self.me {
+            return Ok(());
+        }
+        if !self.config.contains(target) {
+            return Err(TransferError::NotVoter);
+        }
+        println!("{} transfer leadership to {}", self.me, target);
+        self.transferee = target;
+        self.transfer_deadline = self.now + TRANSFER_TICKS;
+        // otherwise heartbeats bring the target up to date first
+        if self.match_index[target as usize] == self.last_index() {
+            self.notify_transferee(target);
+        }
+        Ok(())
+    }
+
+    // tell server i, whose log is up to date, to start an election right away.
+    fn notify_transferee(&mut self, i: i32) {
+        self.lease_revoked = true;
+        let args = TimeoutNowArgs { term: self.current_term, leader_id: self.me };
+        self.send(i, Body::TimeoutNow(args));
+    }
+
+    // implement TimeoutNow RPC.
+    fn timeout_now(&mut self, from: i32, args: &TimeoutNowArgs) {
+        let reply = TimeoutNowReply { term: self.current_term };
+        self.send(from, Body::TimeoutNowReply(reply));
+        if args.term == self.current_term && self.config.contains(self.me) {
+            if let Follower = self.state {
+                println!("{} asked by {} to start election", self.me, args.leader_id);
+                // no pre vote, the leader itself asked us to take over
+                self.start_election(true);
+            }
+        }
+    }
+
+    // ReadIndex: start a linearizable read. we make sure we are still the
+    // leader by waiting until a majority answered heartbeats sent after the
+    // call, see read_ready.
+    pub fn read_index(&mut self) -> Result<ReadIndex, ReadError> {
+        if let Leader = self.state {} else {
+            return Err(ReadError::NotLeader);
+        }
+        let term = self.current_term;
+        if self.lease_valid() {
+            return Ok(ReadIndex { term, round: 0 });
+        }
+        self.broadcast();  // don't wait for the next heartbeat
+        Ok(ReadIndex { term, round: self.round })
+    }
+
+    // the commit index read may be served at once the service has applied it,
+    // None while the majority has not answered yet.
+    pub fn read_ready(&self, read: ReadIndex) -> Result<Option<usize>, ReadError> {
+        if self.current_term != read.term || !matches!(self.state, Leader) {
+            return Err(ReadError::NotLeader);
+        }
+        // until our no-op is committed we don't know the latest commit index
+        if self.term_at(self.commit_index) == read.term && self.acked(|i| self.ack_round[i] >= read.round) {
+            return Ok(Some(self.commit_index));
+        }
+        Ok(None)
+    }
+
+    // no other leader can be elected while a majority answered requests we sent
+    // within the last LEASE_TICKS: those voters refuse to vote until
+    // MIN_ELECTION_TICKS after they heard from us.
+    fn lease_valid(&self) -> bool {
+        self.lease_read && self.transferee == -1 && !self.lease_revoked
+            && self.term_at(self.commit_index) == self.current_term
+            && self.acked_since(self.now.saturating_sub(LEASE_TICKS))
+    }
+
+    // whether a majority, counting ourselves, answered requests we sent at or after tick since.
+    fn acked_since(&self, since: u64) -> bool {
+        self.acked(|i| self.ack_round[i] > 0 && self.ack_tick[i] >= since)
+    }
+
+    // whether ourselves and the servers f holds for form a majority.
+    fn acked<F: Fn(usize) -> bool>(&self, f: F) -> bool {
+        let mut acks = vec![self.me];
+        for m in self.config.members() {
+            if m.id != self.me && f(m.id as usize) {
+                acks.push(m.id);
+            }
+        }
+        self.config.has_quorum(&acks)
+    }
+
+    // leader try to commit
+    fn leader_commit(&mut self) {
+        if let Leader = self.state {} else {
+            return;
+        }
+        let majority = self.config.quorum_index(&self.match_index);  //match index of majority
+
+        // only commit current term's entry
+        if self.commit_index < majority && self.term_at(majority) == self.current_term {
+            self.commit_to(majority);
+        }
+    }
+
+    // commit index and all indices preceding index
+    fn commit_to(&mut self, index: usize) {
+        while self.commit_index < index && self.commit_index < self.last_index() {
+            let i = self.commit_index+1;
+            self.commit_index = i;
+            let config = self.entry(i).config.clone();
+            let msg = ApplyMsg {
+                command: self.entry(i).command.clone(),
+                valid: config.is_none() && !self.entry(i).command.is_empty(),
+                index: i,
+                term: self.entry(i).term,
+                snapshot: Vec::new(),
+                config: config.clone(),
+            };
+            self.apply.push(msg);
+            if let Some(config) = config {
+                self.commit_config(i, config);
+            }
+        }
+    }
+
+    // mark the hard state for saving, ready hands it out before any message
+    // sent after the change.
+    fn persist(&mut self) {
+        self.dirty = true;
+    }
+
+    fn encode_state(&self) -> Vec<u8> {
+        serialize(&(self.current_term, self.vote_for, self.last_included_index, self.last_included_term,
+            &self.snapshot_config, &self.log)).unwrap()
+    }
+
+    // restore previously persisted state.
+    fn restore(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let (current_term, vote_for, last_included_index, last_included_term, snapshot_config, log): HardState =
+            deserialize(data).unwrap();
+        self.current_term = current_term;
+        self.vote_for = vote_for;
+        self.last_included_index = last_included_index;
+        self.last_included_term = last_included_term;
+        self.snapshot_config = snapshot_config;
+        self.log = log;
+    }
+
+    // the service has saved everything up to and including index into snapshot,
+    // discard those log entries. index must already be committed.
+    pub fn compact(&mut self, index: usize, snapshot: Vec<u8>) {
+        if index <= self.last_included_index || index > self.commit_index {
+            return;
+        }
+        let term = self.term_at(index);
+        let (_, config) = self.config_at(index);
+        let offset = self.last_included_index;
+        self.log.drain(..index-offset);  // entry at index becomes log[0]
+        self.log[0].command = Vec::new();
+        self.log[0].config = None;
+        self.snapshot_config = config;
+        self.last_included_index = index;
+        self.last_included_term = term;
+        self.snapshot = snapshot;
+        self.snapshot_dirty = true;
+    }
+
+    // implement InstallSnapshot RPC.
+    fn install_snapshot(&mut self, from: i32, args: InstallSnapshotArgs) {
+        let mut reply = InstallSnapshotReply {
+            term: self.current_term,
+            last_included_index: args.last_included_index,
+            round: args.round,
+        };
+        if args.term < self.current_term { // expired leader
+            self.send(from, Body::InstallSnapshotReply(reply));
+            return;
+        }
+        self.reset_election_timer();
+        self.leader_contact = self.now;
+        if args.term > self.current_term {
+            self.current_term = args.term;
+            self.vote_for = -1;
+            reply.term = self.current_term;
+        }
+        self.state = Follower;
+        self.leader = Some((args.term, args.leader_id));
+
+        // we already have everything in this snapshot
+        if args.last_included_index <= self.commit_index {
+            self.persist();
+            self.send(from, Body::InstallSnapshotReply(reply));
+            return;
+        }
+
+        // keep the entries following the snapshot if our log agrees with it
+        let index = args.last_included_index;
+        if index <= self.last_index() && self.term_at(index) == args.last_included_term {
+            let offset = self.last_included_index;
+            self.log.drain(..index-offset);
+            self.log[0].command = Vec::new();
+            self.log[0].config = None;
+        } else {
+            self.log = vec![LogEntry { term: args.last_included_term, command: Vec::new(), config: None }];
+        }
+        self.last_included_index = index;
+        self.last_included_term = args.last_included_term;
+        self.commit_index = index;
+        self.snapshot_config = args.config;
+        self.reload_config();
+        self.snapshot = args.data.clone();
+        self.snapshot_dirty = true;
+
+        let msg = ApplyMsg {
+            valid: false,
+            index,
+            term: args.last_included_term,
+            command: Vec::new(),
+            snapshot: args.data,
+            config: None,
+        };
+        self.apply.push(msg);
+        self.send(from, Body::InstallSnapshotReply(reply));
+    }
+
+    // start adding a server to the cluster, the leader first switches to a
+    // joint configuration of the old and new servers.
+    // return values: index and term of the joint configuration entry
+    pub fn add_server(&mut self, id: i32, addr: String) -> Result<(usize, u64), ConfigError> {
+        self.check_config_change()?;
+        if self.config.contains(id) {
+            return Err(ConfigError::AlreadyMember);
+        }
+        let old_servers = self.config.servers.clone();
+        let mut servers = old_servers.clone();
+        servers.push(Member { id, addr });
+        Ok(self.propose_config(Configuration { servers, old_servers }))
+    }
+
+    // start removing a server from the cluster, see add_server.
+    pub fn remove_server(&mut self, id: i32) -> Result<(usize, u64), ConfigError> {
+        self.check_config_change()?;
+        if !self.config.contains(id) {
+            return Err(ConfigError::NotMember);
+        }
+        let old_servers = self.config.servers.clone();
+        let servers: Vec<Member> = old_servers.iter().filter(|m| m.id != id).cloned().collect();
+        if servers.is_empty() {
+            return Err(ConfigError::EmptyConfiguration);
+        }
+        Ok(self.propose_config(Configuration { servers, old_servers }))
+    }
+
+    // only a leader may change the configuration, one change at a time.
+    fn check_config_change(&self) -> Result<(), ConfigError> {
+        if let Leader = self.state {
+            if self.config.is_joint() || self.config_index > self.commit_index {
+                return Err(ConfigError::InProgress);
+            }
+            return Ok(());
+        }
+        Err(ConfigError::NotLeader)
+    }
+
+    // append a configuration entry, it takes effect right away.
+    fn propose_config(&mut self, config: Configuration) -> (usize, u64) {
+        let (index, term) = (self.last_index()+1, self.current_term);
+        println!("{} propose configuration {:?} at {}", self.me, config, index);
+        self.log.push(LogEntry { term, command: Vec::new(), config: Some(config) });
+        let me = self.me as usize;
+        self.match_index[me] = index;
+        self.reload_config();
+        self.persist();
+        (index, term)
+    }
+
+    // called once the configuration entry at index is committed.
+    fn commit_config(&mut self, index: usize, config: Configuration) {
+        if config.is_joint() {
+            // both majorities have the joint configuration, move on to the new one
+            if let Leader = self.state {
+                if index == self.config_index {
+                    self.propose_config(Configuration { servers: config.servers, old_servers: Vec::new() });
+                }
+            }
+            return;
+        }
+        // forget servers which left the cluster
+        let current = &self.config;
+        self.peers.retain(|&id| config.contains(id) || current.contains(id));
+        if let Leader = self.state {
+            if !config.contains(self.me) {
+                println!("{} removed from the cluster, step down", self.me);
+                self.state = Follower;
+                self.leader = None;
+                self.reset_election_timer();
+            }
+        }
+    }
+
+    // use the latest configuration in the log, or the snapshot's one.
+    fn reload_config(&mut self) {
+        let (index, config) = self.config_at(self.last_index());
+        for m in config.members() {
+            self.connect(m.id);
+        }
+        self.config = config;
+        self.config_index = index;
+    }
+
+    // latest configuration at or before index, and the index it was set at.
+    fn config_at(&self, index: usize) -> (usize, Configuration) {
+        for i in (self.last_included_index+1..=index).rev() {
+            if let Some(ref config) = self.entry(i).config {
+                return (i, config.clone());
+            }
+        }
+        (self.last_included_index, self.snapshot_config.clone())
+    }
+
+    // start tracking server id, and replicate to it unless it is us.
+    fn connect(&mut self, id: i32) {
+        let i = id as usize;
+        let next = self.last_index()+1;
+        if i >= self.next_index.len() {
+            self.next_index.resize(i+1, next);
+            self.match_index.resize(i+1, 0);
+            self.ack_round.resize(i+1, 0);
+            self.ack_tick.resize(i+1, 0);
+        }
+        if id != self.me && !self.peers.contains(&id) {
+            self.peers.push(id);
+            self.next_index[i] = next;
+            self.match_index[i] = 0;
+        }
+    }
+
+    fn send(&mut self, to: i32, body: Body) {
+        self.msgs.push(Message { from: self.me, to, body });
+    }
+
+    pub fn last_index(&self) -> usize {
+        self.last_included_index + self.log.len() - 1
+    }
+
+    // log entry at the given absolute index, must not be compacted yet.
+    fn entry(&self, index: usize) -> &LogEntry {
+        &self.log[index - self.last_included_index]
+    }
+
+    fn term_at(&self, index: usize) -> u64 {
+        self.entry(index).term
+    }
+}
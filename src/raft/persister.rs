@@ -0,0 +1,100 @@
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::PathBuf;
+
+const RAFT_STATE_FILE: &str = "raft_state";
+const SNAPSHOT_FILE: &str = "snapshot";
+
+// persister keeps raft's hard state in a per-node data directory.
+// every save goes to a temp file which is fsync'd and then renamed over
+// the old one, so a crash leaves either the old or the new state on disk.
+#[derive(Clone)]
+pub struct Persister {
+    dir: PathBuf,
+}
+
+impl Persister {
+    pub fn new(dir: &str) -> Persister {
+        fs::create_dir_all(dir).unwrap();
+        Persister {
+            dir: PathBuf::from(dir),
+        }
+    }
+
+    pub fn save_raft_state(&self, state: &[u8]) {
+        self.write_atomic(RAFT_STATE_FILE, state);
+    }
+
+    // return an empty vec if nothing has been persisted yet
+    pub fn read_raft_state(&self) -> Vec<u8> {
+        fs::read(self.dir.join(RAFT_STATE_FILE)).unwrap_or_default()
+    }
+
+    // size in bytes of the persisted raft state, used to decide when to snapshot
+    pub fn raft_state_size(&self) -> usize {
+        fs::metadata(self.dir.join(RAFT_STATE_FILE)).map(|m| m.len() as usize).unwrap_or(0)
+    }
+
+    // the snapshot is written first: if we crash in between, the old raft state
+    // still covers everything the new snapshot does and the extra entries are
+    // simply applied again on top of it.
+    pub fn save_state_and_snapshot(&self, state: &[u8], snapshot: &[u8]) {
+        self.write_atomic(SNAPSHOT_FILE, snapshot);
+        self.write_atomic(RAFT_STATE_FILE, state);
+    }
+
+    pub fn read_snapshot(&self) -> Vec<u8> {
+        fs::read(self.dir.join(SNAPSHOT_FILE)).unwrap_or_default()
+    }
+
+    fn write_atomic(&self, name: &str, data: &[u8]) {
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        let mut f = File::create(&tmp).unwrap();
+        f.write_all(data).unwrap();
+        f.sync_all().unwrap();
+        fs::rename(&tmp, self.dir.join(name)).unwrap();
+        // sync the directory too, otherwise the rename itself may be lost
+        File::open(&self.dir).and_then(|d| d.sync_all()).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn persister_test() {
+        let dir = env::temp_dir().join("kv-service-persister-test");
+        let _ = fs::remove_dir_all(&dir);
+        let p = Persister::new(dir.to_str().unwrap());
+        assert!(p.read_raft_state().is_empty());
+
+        p.save_raft_state(&[1, 2, 3]);
+        assert_eq!(p.read_raft_state(), vec![1, 2, 3]);
+
+        // state survives a new persister on the same directory
+        p.save_raft_state(&[4, 5]);
+        let p2 = Persister::new(dir.to_str().unwrap());
+        assert_eq!(p2.read_raft_state(), vec![4, 5]);
+        assert_eq!(p2.raft_state_size(), 2);
+    }
+
+    #[test]
+    fn persister_snapshot_test() {
+        let dir = env::temp_dir().join("kv-service-persister-snapshot-test");
+        let _ = fs::remove_dir_all(&dir);
+        let p = Persister::new(dir.to_str().unwrap());
+        assert!(p.read_snapshot().is_empty());
+        assert_eq!(p.raft_state_size(), 0);
+
+        p.save_state_and_snapshot(&[1], &[7, 8, 9]);
+        assert_eq!(p.read_raft_state(), vec![1]);
+        assert_eq!(p.read_snapshot(), vec![7, 8, 9]);
+
+        // saving raft state alone keeps the snapshot
+        p.save_raft_state(&[2, 3]);
+        assert_eq!(p.read_snapshot(), vec![7, 8, 9]);
+        assert_eq!(p.raft_state_size(), 2);
+    }
+}
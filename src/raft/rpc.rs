@@ -1,19 +1,27 @@
 use bincode::{serialize, deserialize};
+use rand::Rng;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 
-use std::io::prelude::*;
+use std::io::{self, prelude::*};
 use std::net::TcpListener;
-use std::net::TcpStream;
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
 use std::collections::HashMap;
 use std::thread;
-use std::sync::mpsc::SyncSender;
-use std::sync::mpsc::Receiver;
-use std::sync::{Mutex, Arc};
+use std::time::{Duration, Instant};
+use std::sync::mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender};
+use std::sync::{Mutex, Arc, OnceLock, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicU64, AtomicUsize, Ordering};
 
-
-//const CALLBACK_NUMS : u32 = 2;
+const MAX_FRAME_SIZE : usize = 64 << 20;   // refuse messages bigger than this, snapshots included
+const MAX_WORKERS : usize = 256;    // threads running requests for one service, more requests queue up
+const CALL_TIMEOUT : u64 = 10000;   // ms, for calls which don't set their own deadline
+const REPLY_TIMEOUT : u64 = 5000;   // ms, a client which takes longer to read a reply loses its connection
+const MEM_PREFIX : &str = "mem:";   // addresses of networks living in this process, e.g. "mem:kv-0"
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 struct ReqMsg {
+    id : u64,       // unique per process, the reply carries it back
     end_name : String,
     svc_meth : String,
     args_type : String,
@@ -23,8 +31,10 @@ struct ReqMsg {
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 struct ReplyMsg {
+    id : u64,
     ok : bool,
     reply : Vec<u8>,
+    err : Option<ServerError>,  // set when ok is false because of the server
 }
 
 #[derive(Clone)]
@@ -34,6 +44,12 @@ pub struct Client {
     // done : TcpStream,
 }
 
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Client {
     pub fn new() -> Client {
         Client {
@@ -43,76 +59,450 @@ impl Client {
     }
 
     pub fn call(&self, svc_meth : String, args : Vec<u8>) -> (Vec<u8>, bool) {
+        match self.call_with_timeout(svc_meth, args, Duration::from_millis(CALL_TIMEOUT)) {
+            Ok(reply) => (reply, true),
+            Err(_) => (Vec::new(), false),
+        }
+    }
+
+    // call svc_meth and wait at most timeout for the reply, connecting included.
+    pub fn call_with_timeout(&self, svc_meth : String, args : Vec<u8>, timeout : Duration) -> Result<Vec<u8>, RpcError> {
+        let deadline = Instant::now() + timeout;
         let req = ReqMsg {
+            id : NEXT_REQ_ID.fetch_add(1, Ordering::SeqCst),
             end_name : self.end_name.clone(),
-            svc_meth : svc_meth,
+            svc_meth,
             args_type : String::from("bin"),
-            args : args,
+            args,
         };
 
-        if let Ok(mut ch) = TcpStream::connect(self.server_addr.clone()) {
-            let req = serialize(&req).unwrap();
-            ch.write(&req).unwrap();
+        let transport = transport(&self.server_addr, timeout)?;
+        let reply = transport.call(req, deadline)?;
+        match reply.err {
+            None => Ok(reply.reply),
+            Some(ServerError::UnknownService(name)) | Some(ServerError::UnknownMethod(name)) => {
+                Err(RpcError::UnknownMethod(name))
+            },
+            Some(ServerError::Unreachable) => Err(RpcError::ConnectFailed),
+            Some(err) => Err(RpcError::RemoteError(format!("{:?}", err))),
+        }
+    }
+}
 
-            let mut buffer = [0; 4096];
-            if let Ok(size) = ch.read(&mut buffer) {
-                let reply : ReplyMsg = deserialize(&buffer[..size]).unwrap();
-                if reply.ok == false {
-                }
-                return (reply.reply, reply.ok);
+// why a call failed.
+#[derive(PartialEq, Clone, Debug)]
+pub enum RpcError {
+    ConnectFailed,          // the server is unreachable, or the connection broke during the call
+    Timeout,
+    Decode,                 // the reply does not decode into what the caller expects
+    UnknownMethod(String),  // the server does not offer this service or method
+    RemoteError(String),    // the server failed to run the request
+}
+
+static NEXT_REQ_ID : AtomicU64 = AtomicU64::new(1);
+
+// how a call gets to the network serving an address and its reply back.
+trait Transport : Send + Sync {
+    fn call(&self, req : ReqMsg, deadline : Instant) -> Result<ReplyMsg, RpcError>;
+}
+
+// "mem:" addresses go straight to a network of this process, anything else over tcp.
+fn transport(addr : &str, timeout : Duration) -> Result<Arc<dyn Transport>, RpcError> {
+    if addr.starts_with(MEM_PREFIX) {
+        let rn = mem_networks().lock().unwrap().get(addr).cloned().ok_or(RpcError::ConnectFailed)?;
+        return Ok(Arc::new(MemTransport { rn }));
+    }
+    Ok(Connection::get(addr, timeout)?)
+}
+
+// networks listening on "mem:" addresses, by address
+fn mem_networks() -> &'static Mutex<HashMap<String, ANetwork>> {
+    static NETWORKS : OnceLock<Mutex<HashMap<String, ANetwork>>> = OnceLock::new();
+    NETWORKS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// the network listening on a "mem:" address, for tests to break the
+// connections of servers they only know by address.
+pub fn mem_network(addr : &str) -> Option<ANetwork> {
+    mem_networks().lock().unwrap().get(addr).cloned()
+}
+
+// hands requests to the network's workers without sockets or encoding,
+// the faults set up on the network apply just like over tcp.
+struct MemTransport {
+    rn : ANetwork,
+}
+
+impl Transport for MemTransport {
+    fn call(&self, req : ReqMsg, deadline : Instant) -> Result<ReplyMsg, RpcError> {
+        let (sx, rx) = sync_channel(1);
+        let rn = self.rn.clone();
+        self.rn.workers(&req.svc_meth).execute(Box::new(move || {
+            if let Some(reply) = deliver(&rn, req) {
+                let _ = sx.send(reply);
             }
-            else {
-                 println!("[RPC] read from {} error", &self.server_addr);
-                return (Vec::new(), false);
+        }));
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(timeout) {
+            Ok(reply) => Ok(reply),
+            Err(RecvTimeoutError::Timeout) => Err(RpcError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => {
+                // no reply is coming, over tcp we would only notice at the deadline
+                thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                Err(RpcError::Timeout)
+            },
+        }
+    }
+}
+
+// connections shared by all clients of this process, by server address
+fn pool() -> &'static Mutex<HashMap<String, Arc<Connection>>> {
+    static POOL : OnceLock<Mutex<HashMap<String, Arc<Connection>>>> = OnceLock::new();
+    POOL.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+type Outgoing = (Vec<u8>, Instant);     // a request frame and the deadline of its call
+
+// a long lived connection to one server. any number of calls share it, a
+// writer thread sends their requests and a reader thread hands every reply
+// to the call waiting for its id, so a call only ever waits for its deadline.
+// once the connection breaks it leaves the pool and the next call dials again.
+struct Connection {
+    addr : String,
+    stream : TcpStream,     // to shut down, the threads have their own handles
+    outgoing : Mutex<Option<Sender<Outgoing>>>,  // to the writer thread, None once closed
+    pending : Mutex<HashMap<u64, SyncSender<ReplyMsg>>>,  // calls waiting for a reply, by request id
+    broken : AtomicBool,
+}
+
+impl Connection {
+    fn get(addr : &str, timeout : Duration) -> Result<Arc<Connection>, RpcError> {
+        if let Some(conn) = pool().lock().unwrap().get(addr) {
+            if !conn.broken.load(Ordering::SeqCst) {
+                return Ok(conn.clone());
+            }
+        }
+        let conn = Self::dial(addr, timeout)?;
+        Ok(Self::share(conn))
+    }
+
+    fn dial(addr : &str, timeout : Duration) -> Result<Arc<Connection>, RpcError> {
+        let stream = Self::connect(addr, timeout).map_err(|_| RpcError::ConnectFailed)?;
+        let reader = stream.try_clone().map_err(|_| RpcError::ConnectFailed)?;
+        let writer = stream.try_clone().map_err(|_| RpcError::ConnectFailed)?;
+        let (sx, rx) = channel();
+        let conn = Arc::new(Connection {
+            addr : addr.to_string(),
+            stream,
+            outgoing : Mutex::new(Some(sx)),
+            pending : Mutex::new(HashMap::new()),
+            broken : AtomicBool::new(false),
+        });
+        let conn1 = conn.clone();
+        thread::spawn(move || { conn1.read_replies(reader) });
+        let conn2 = conn.clone();
+        thread::spawn(move || { conn2.write_requests(writer, rx) });
+        Ok(conn)
+    }
+
+    // put conn in the pool. somebody may have dialed at the same time, then
+    // we keep theirs and hang up conn.
+    fn share(conn : Arc<Connection>) -> Arc<Connection> {
+        let mut pool = pool().lock().unwrap();
+        if let Some(other) = pool.get(&conn.addr) {
+            if !other.broken.load(Ordering::SeqCst) {
+                let other = other.clone();
+                drop(pool);
+                conn.close();
+                return other;
+            }
+        }
+        pool.insert(conn.addr.clone(), conn.clone());
+        conn
+    }
+
+    fn connect(addr : &str, timeout : Duration) -> io::Result<TcpStream> {
+        let addr = addr.to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address"))?;
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+
+    fn read_replies(&self, mut stream : TcpStream) {
+        while let Ok(buf) = read_frame(&mut stream) {
+            let reply : ReplyMsg = match deserialize(&buf) {
+                Ok(reply) => reply,
+                Err(_) => break,
+            };
+            if let Some(sx) = self.pending.lock().unwrap().remove(&reply.id) {
+                let _ = sx.send(reply);
             }
         }
-        else {
-//             println!("[RPC] can not connect to {}", &self.server_addr);
-            return (Vec::new(), false);
+        self.close();
+    }
+
+    // send requests in the order they were made, each given until the
+    // deadline of its call. one which did not make it breaks the connection,
+    // the server would only see part of it.
+    fn write_requests(&self, mut stream : TcpStream, outgoing : Receiver<Outgoing>) {
+        for (buf, deadline) in outgoing {
+            if Instant::now() >= deadline {
+                continue;   // its call gave up already
+            }
+            if write_frame_until(&mut stream, &buf, deadline).is_err() {
+                break;
+            }
+        }
+        self.close();
+    }
+
+    // fail every call in flight and drop out of the pool.
+    fn close(&self) {
+        self.broken.store(true, Ordering::SeqCst);
+        let _ = self.stream.shutdown(Shutdown::Both);
+        self.outgoing.lock().unwrap().take();
+        self.pending.lock().unwrap().clear();
+        let mut pool = pool().lock().unwrap();
+        if let Some(conn) = pool.get(&self.addr) {
+            if std::ptr::eq(conn.as_ref(), self) {
+                pool.remove(&self.addr);
+            }
+        }
+    }
+}
+
+impl Transport for Connection {
+    fn call(&self, req : ReqMsg, deadline : Instant) -> Result<ReplyMsg, RpcError> {
+        let (sx, rx) = sync_channel(1);
+        self.pending.lock().unwrap().insert(req.id, sx);
+        if self.broken.load(Ordering::SeqCst) {
+            // closed before it could see us
+            self.pending.lock().unwrap().remove(&req.id);
+            return Err(RpcError::ConnectFailed);
+        }
+        let buf = serialize(&req).unwrap();
+        let sent = match *self.outgoing.lock().unwrap() {
+            Some(ref sx) => sx.send((buf, deadline)).is_ok(),
+            None => false,
+        };
+        if !sent {
+            self.pending.lock().unwrap().remove(&req.id);
+            return Err(RpcError::ConnectFailed);
+        }
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(timeout) {
+            Ok(reply) => Ok(reply),
+            Err(RecvTimeoutError::Timeout) => {
+                // a late reply finds nobody waiting and is dropped
+                self.pending.lock().unwrap().remove(&req.id);
+                Err(RpcError::Timeout)
+            },
+            Err(RecvTimeoutError::Disconnected) => Err(RpcError::ConnectFailed),  // the connection was closed
         }
     }
 }
 
-// #[derive(Debug)]
+// why a server could not run a request.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub enum ServerError {
+    UnknownService(String),
+    UnknownMethod(String),
+    BadArgs,        // the args don't decode into what the method takes
+    Unreachable,    // fault injection, the request or its reply was dropped
+}
+
+pub type Handler = Box<dyn Fn(&[u8]) -> Result<Vec<u8>, ServerError> + Send + Sync>;
+
+// a named set of methods, e.g. "Raft" or "KV". a request for
+// "Raft.AppendEntries" runs method "AppendEntries" of service "Raft".
+pub struct Service {
+    name : String,
+    methods : HashMap<String, Handler>,
+}
+
+impl Service {
+    pub fn new(name : &str) -> Service {
+        Service {
+            name : name.to_string(),
+            methods : HashMap::new(),
+        }
+    }
+
+    // register a method, its args and reply are encoded with bincode.
+    pub fn add_method<A, R, F>(&mut self, name : &str, f : F)
+        where A : DeserializeOwned, R : Serialize, F : Fn(A) -> R + Send + Sync + 'static {
+        let handler = move |args : &[u8]| {
+            let args = deserialize(args).map_err(|_| ServerError::BadArgs)?;
+            Ok(serialize(&f(args)).unwrap())
+        };
+        self.methods.insert(name.to_string(), Box::new(handler));
+    }
+
+    fn dispatch(&self, method_name : &str, args : &[u8]) -> Result<Vec<u8>, ServerError> {
+        match self.methods.get(method_name) {
+            Some(handler) => handler(args),
+            None => Err(ServerError::UnknownMethod(format!("{}.{}", self.name, method_name))),
+        }
+    }
+}
+
+// the server side of the rpc layer, one per listening address, which is
+// either a tcp address or a "mem:" name reachable from this process only.
+// tests can make it misbehave like the 6.824 labrpc network: faults apply
+// to the requests this server receives and to the replies it sends back.
 pub struct Network {
     addr    :       String,
-    pub reliable    :   bool,
-    pub long_delays   :  bool,                        // pause a long time on send on disabled connection
-    pub long_reordering : bool,                        // sometimes delay replies a long time
+    reliable    :   AtomicBool,                     // when false drop some requests and replies, delay the rest a little
+    long_delays   :  AtomicBool,                    // pause a long time on send on disabled connection
+    long_reordering : AtomicBool,                   // sometimes delay replies a long time
+    drop_rate  :    Mutex<f64>,                     // fraction of requests, and of replies, an unreliable network drops
     ends     :      Mutex<HashMap<String, bool>>,  // ends, by name
-    servers    :    HashMap<String, bool>,     // servers, by name
-    // enabled   :     HashMap<String, bool>,        // by end name
+    services   :    RwLock<HashMap<String, Arc<Service>>>,  // services, by name
+    enabled   :     Mutex<HashMap<String, bool>>,   // by end name of the caller, missing means enabled
     // connections  :  HashMap<String, String>, // end_name -> servername
     // endCh    :      (Sender<ReqMsg>, Receiver<ReqMsg>),
     // done      :     (Sender<()>, Receiver<()>), // closed when Network is cleaned up
-    count     :     Mutex<u32>,
-    req_send : Vec<SyncSender<Vec<u8>>>,
-    reply_recv : Arc<Mutex<Vec<Receiver<(Vec<u8>, bool)>>>>,
+    count     :     Mutex<u32>,                     // requests delivered to a service
+    workers   :     Mutex<HashMap<String, Arc<WorkerPool>>>,  // by service name, "" for unknown services
 }
 
 pub type ANetwork = Arc<Network>;
 
-pub fn make_network(addr : String, req_send : Vec<SyncSender<Vec<u8>>>, reply_recv : Vec<Receiver<(Vec<u8>, bool)>>) -> ANetwork {
-    let mut rn = Network {
-        addr : addr,
-        reliable : true,
-        long_delays : false,
-        long_reordering : false,
+type Job = Box<dyn FnOnce() + Send>;
+
+// threads running requests. handlers may block for a long time, e.g. a KV.Get
+// waiting for raft, so we start another thread whenever all of them are busy,
+// up to MAX_WORKERS. idle threads wait for the next request. every service has
+// its own pool, so a service full of blocked handlers holds up no other.
+struct WorkerPool {
+    sender : Mutex<Sender<Job>>,
+    receiver : Arc<Mutex<Receiver<Job>>>,
+    workers : AtomicUsize,
+    spare : Arc<AtomicIsize>,  // threads not running a job less jobs not yet picked up
+}
+
+impl WorkerPool {
+    fn new() -> WorkerPool {
+        let (sender, receiver) = channel();
+        WorkerPool {
+            sender : Mutex::new(sender),
+            receiver : Arc::new(Mutex::new(receiver)),
+            workers : AtomicUsize::new(0),
+            spare : Arc::new(AtomicIsize::new(0)),
+        }
+    }
+
+    fn execute(&self, job : Job) {
+        // a thread waiting in recv may be about to take a job queued before
+        // ours, so only a spare one left after all queued jobs will do
+        let spare = self.spare.fetch_sub(1, Ordering::SeqCst) > 0;
+        if !spare && self.workers.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < MAX_WORKERS).then_some(n + 1)).is_ok() {
+            self.spare.fetch_add(1, Ordering::SeqCst);
+            let receiver = self.receiver.clone();
+            let spare = self.spare.clone();
+            thread::spawn(move || {
+                loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => return,
+                    }
+                    spare.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+        }
+        self.sender.lock().unwrap().send(job).unwrap();
+    }
+}
+
+impl Network {
+    // the pool running requests for svc_meth's service.
+    fn workers(&self, svc_meth : &str) -> Arc<WorkerPool> {
+        let name = svc_meth.split('.').next().unwrap_or("");
+        let name = if self.services.read().unwrap().contains_key(name) { name } else { "" };
+        self.workers.lock().unwrap()
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(WorkerPool::new()))
+            .clone()
+    }
+
+    // make svc's methods callable, replacing any service of the same name.
+    pub fn add_service(&self, svc : Service) {
+        let mut services = self.services.write().unwrap();
+        services.insert(svc.name.clone(), Arc::new(svc));
+    }
+
+    // requests from a disabled end never reach a service.
+    pub fn enable(&self, end_name : &str, enabled : bool) {
+        self.enabled.lock().unwrap().insert(end_name.to_string(), enabled);
+    }
+
+    pub fn set_reliable(&self, reliable : bool) {
+        self.reliable.store(reliable, Ordering::SeqCst);
+    }
+
+    // with long delays a disabled end gets no answer at all and the caller
+    // waits for its deadline, otherwise the call fails right away.
+    pub fn set_long_delays(&self, long_delays : bool) {
+        self.long_delays.store(long_delays, Ordering::SeqCst);
+    }
+
+    pub fn set_long_reordering(&self, long_reordering : bool) {
+        self.long_reordering.store(long_reordering, Ordering::SeqCst);
+    }
+
+    pub fn set_drop_rate(&self, drop_rate : f64) {
+        *self.drop_rate.lock().unwrap() = drop_rate;
+    }
+
+    // number of requests delivered so far.
+    pub fn count(&self) -> u32 {
+        *self.count.lock().unwrap()
+    }
+}
+
+// name of the end server `from` calls server `to` with.
+pub fn end_name(from : i32, to : i32) -> String {
+    format!("client{}to{}", from, to)
+}
+
+// nets[i] is the network of server i. servers only reach those in the same
+// group, a server missing from every group is cut off from everybody.
+pub fn partition(nets : &[ANetwork], groups : &[Vec<i32>]) {
+    let group_of = |id : i32| groups.iter().position(|g| g.contains(&id));
+    for (to, net) in nets.iter().enumerate() {
+        for from in 0..nets.len() {
+            let (from, to) = (from as i32, to as i32);
+            let connected = from == to || (group_of(from).is_some() && group_of(from) == group_of(to));
+            net.enable(&end_name(from, to), connected);
+        }
+    }
+}
+
+pub fn make_network(addr : String) -> ANetwork {
+    let rn = Network {
+        addr,
+        reliable : AtomicBool::new(true),
+        long_delays : AtomicBool::new(false),
+        long_reordering : AtomicBool::new(false),
+        drop_rate : Mutex::new(0.1),
         ends : Mutex::new(HashMap::new()),
-        servers : HashMap::new(),
-        // enabled : HashMap::new(),
+        services : RwLock::new(HashMap::new()),
+        enabled : Mutex::new(HashMap::new()),
         // connections : HashMap::new(),
         // endCh : channel(),
         // done : channel(),
         count : Mutex::new(0),
-        req_send : req_send,
-        reply_recv : Arc::new(Mutex::new(reply_recv)),
+        workers : Mutex::new(HashMap::new()),
     };
 
-        rn.servers.insert(String::from("Raft"), true);
-        rn.servers.insert(String::from("KV"), true);
-
         let rn = Arc::new(rn);
+        if rn.addr.starts_with(MEM_PREFIX) {
+            // replaces whatever served the address before, e.g. a server being restarted
+            mem_networks().lock().unwrap().insert(rn.addr.clone(), rn.clone());
+            return rn;
+        }
         let rnt = rn.clone();
 
         thread::spawn(move || {
@@ -121,134 +511,180 @@ pub fn make_network(addr : String, req_send : Vec<SyncSender<Vec<u8>>>, reply_re
 
             for stream in listener.incoming() {
                 match stream {
-                    Ok(mut streamm) => {
-                        match handle_connection(&rnt, &mut streamm) {
-                            Ok(_) => (),
-                            Err(_err) => {
-                            },
-                        }
+                    Ok(streamm) => {
+                        let rn = rnt.clone();
+                        // clients keep their connection open, serve each on its own
+                        thread::spawn(move || {
+                            let _ = handle_connection(rn, streamm);
+                        });
                     },
                     Err(err) => println!("{:?}", err),
                 }
-                // thread::spawn(move || {
-                //      loop {
-                //      }
-                // });
-                // break;
             }
         });
 
         rn
 }
 
-fn handle_connection(rn : &ANetwork, stream:&mut TcpStream) -> Result<(), std::io::Error> {
-    let mut buffer = [0; 4096];
-    let size = stream.read(&mut buffer)?;
-    let req : ReqMsg = match deserialize(&buffer[..size]){
-        Ok(res) => res,
-        Err(_) => {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other,"a"));
-        },
-    };
-
-    let reply_msg = dispatch(rn, req);
+// serve requests until the client hangs up. requests run on the worker pool
+// so a slow one does not hold up those behind it, replies go out in whatever
+// order they finish and the client matches them up by id.
+fn handle_connection(rn : ANetwork, mut stream : TcpStream) -> Result<(), std::io::Error> {
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    loop {
+        let buffer = read_frame(&mut stream)?;
+        let req : ReqMsg = match deserialize(&buffer){
+            Ok(res) => res,
+            Err(err) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            },
+        };
 
-    let reply_msg = serialize(&reply_msg).unwrap();
+        let rn1 = rn.clone();
+        let writer = writer.clone();
+        rn.workers(&req.svc_meth).execute(Box::new(move || {
+            if let Some(reply_msg) = deliver(&rn1, req) {
+                let reply_msg = serialize(&reply_msg).unwrap();
+                let mut w = writer.lock().unwrap();
+                let deadline = Instant::now() + Duration::from_millis(REPLY_TIMEOUT);
+                // a client which stopped reading would hold up every later
+                // reply, and half a frame leaves the stream unusable anyway
+                if write_frame_until(&mut w, &reply_msg, deadline).is_err() {
+                    let _ = w.shutdown(Shutdown::Both);
+                }
+            }
+        }));
+    }
+}
 
-    stream.write(&reply_msg)?;
+// every message on the wire is a 4 byte big endian length followed by that
+// many bytes. we give up at deadline however slowly the peer reads.
+fn write_frame_until(stream : &mut TcpStream, msg : &[u8], deadline : Instant) -> io::Result<()> {
+    if msg.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame too large"));
+    }
+    for mut part in [&(msg.len() as u32).to_be_bytes()[..], msg] {
+        while !part.is_empty() {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "write deadline passed"));
+            }
+            // the timeout holds for a single write, so set it again before each
+            stream.set_write_timeout(Some(timeout))?;
+            match stream.write(part) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "connection closed")),
+                Ok(n) => part = &part[n..],
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+    }
     Ok(())
 }
 
+// read_exact keeps reading until the whole frame arrived, however tcp split it up.
+fn read_frame<R: Read>(r : &mut R) -> io::Result<Vec<u8>> {
+    let mut header = [0; 4];
+    r.read_exact(&mut header)?;
+    let len = u32::from_be_bytes(header) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"));
+    }
+    let mut buf = vec![0; len];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+// run req through the faults set up on rn, None if the caller gets no reply at all.
+fn deliver(rn : &ANetwork, req : ReqMsg) -> Option<ReplyMsg> {
+    let id = req.id;
+    let enabled = rn.enabled.lock().unwrap().get(&req.end_name).cloned().unwrap_or(true);
+    if !enabled {
+        if rn.long_delays.load(Ordering::SeqCst) {
+            return None;
+        }
+        return Some(unreachable(id));
+    }
+
+    let reliable = rn.reliable.load(Ordering::SeqCst);
+    let drop_rate = *rn.drop_rate.lock().unwrap();
+    let mut rng = rand::thread_rng();
+    if !reliable {
+        thread::sleep(Duration::from_millis(rng.gen_range(0, 27)));
+        if rng.gen::<f64>() < drop_rate {
+            return Some(unreachable(id));     // the request got lost
+        }
+    }
+
+    let reply = dispatch(rn, req);
+
+    if !reliable && rng.gen::<f64>() < drop_rate {
+        return Some(unreachable(id));     // executed, but the reply got lost
+    }
+    if rn.long_reordering.load(Ordering::SeqCst) && rng.gen_range(0, 900) < 600 {
+        // later requests overtake this reply
+        thread::sleep(Duration::from_millis(200 + rng.gen_range(0, 2000)));
+    }
+    Some(reply)
+}
+
+fn unreachable(id : u64) -> ReplyMsg {
+    ReplyMsg {
+        id,
+        ok : false,
+        reply : Vec::new(),
+        err : Some(ServerError::Unreachable),
+    }
+}
+
 fn dispatch(rn : &ANetwork, req : ReqMsg) -> ReplyMsg {
-    let mut count = rn.count.lock().unwrap();
-    *count += 1;
-
-    let dot = req.svc_meth.find('.').unwrap();
-
-    let service_name = &req.svc_meth[..dot];
-    let method_name = &req.svc_meth[dot+1..];
-
-    if let Some(_service) = rn.servers.get(service_name) {
-        match method_name {
-            "RequestVote" => {
-                rn.req_send[0].send(req.args).unwrap();
-                let rch_tmp = rn.reply_recv.clone();
-                let rch = rch_tmp.lock().unwrap();
-
-                let (reply, ok) = rch[0].recv().unwrap();
-                return ReplyMsg {
-                    ok : ok,
-                    reply : reply,
-                };
-            },
-            "AppendEntries" => {
-                rn.req_send[1].send(req.args).unwrap();
-                let rch_tmp = rn.reply_recv.clone();
-                let rch = rch_tmp.lock().unwrap();
-
-                let (reply, ok) = rch[1].recv().unwrap();
-                return ReplyMsg {
-                    ok : ok,
-                    reply : reply,
-                };
-            },
-            "Get" => {
-                rn.req_send[2].send(req.args).unwrap();
-                let rch_tmp = rn.reply_recv.clone();
-                let rch = rch_tmp.lock().unwrap();
-
-                let (reply, ok) = rch[2].recv().unwrap();
-                return ReplyMsg {
-                    ok : ok,
-                    reply : reply,
-                };
-            },
-            "PutAppend" => {
-                rn.req_send[3].send(req.args).unwrap();
-                let rch_tmp = rn.reply_recv.clone();
-                let rch = rch_tmp.lock().unwrap();
-
-                let (reply, ok) = rch[3].recv().unwrap();
-                return ReplyMsg {
-                    ok : ok,
-                    reply : reply,
-                };
-            },
-            _ => {
-                println!("labrpc.Server.dispatch(): unknown method {} in {}.{}; expecting one of {:?}",
-                service_name, service_name, method_name, &rn.servers);
-            },
-        };
-        return ReplyMsg {
+    {
+        let mut count = rn.count.lock().unwrap();
+        *count += 1;
+    }
+
+    let (service_name, method_name) = match req.svc_meth.find('.') {
+        Some(dot) => (&req.svc_meth[..dot], &req.svc_meth[dot+1..]),
+        None => (&req.svc_meth[..], ""),
+    };
+
+    // don't hold the lock while the handler runs
+    let service = rn.services.read().unwrap().get(service_name).cloned();
+    let result = match service {
+        Some(service) => service.dispatch(method_name, &req.args),
+        None => Err(ServerError::UnknownService(service_name.to_string())),
+    };
+    match result {
+        Ok(reply) => ReplyMsg {
+            id : req.id,
             ok : true,
-            reply : Vec::new(),
-        };
-    } else {
-        println!("labrpc.Server.dispatch(): unknown service {} in {}.{}; expecting one of {:?}",
-        service_name, service_name, method_name, &rn.servers);
-        return ReplyMsg {
-            ok : false,
-            reply : Vec::new(),
-        };
+            reply,
+            err : None,
+        },
+        Err(err) => {
+            println!("[RPC] dispatch {} failed: {:?}", req.svc_meth, err);
+            ReplyMsg {
+                id : req.id,
+                ok : false,
+                reply : Vec::new(),
+                err : Some(err),
+            }
+        },
     }
 }
 
 pub fn make_end(rn : &ANetwork, end_name : String, server_addr : String) -> Client {
     let mut ends = rn.ends.lock().unwrap();
-    if let Some(_) = ends.get(&end_name) {
+    if ends.insert(end_name.clone(), true).is_some() {
         panic!("client: {} already exist!", &end_name);
-        // return Client;
-    } else {
-        // let stream = TcpStream::connect(server_addr.clone()).unwrap();
-
-        let client = Client {
-            end_name : end_name.clone(),
-            // ch : stream,
-            server_addr : server_addr,
-            // done : TcpStream,
-        };
-        ends.insert(end_name, true);
-        return client;
+    }
+    // let stream = TcpStream::connect(server_addr.clone()).unwrap();
+
+    Client {
+        end_name,
+        // ch : stream,
+        server_addr,
+        // done : TcpStream,
     }
 }
 
@@ -262,8 +698,6 @@ mod tests {
         a : u32,
         peers : Vec<Client>,
         network : ANetwork,
-        reply_sender : Vec<SyncSender<(Vec<u8>, bool)>>,
-        // req_receiver : Vec<Receiver<Vec<u8>>>,
     }
 
     #[derive(Serialize, Deserialize, PartialEq, Debug)]
@@ -274,101 +708,45 @@ mod tests {
         pub last_log_term: u64,
     }
 
-    #[derive(Serialize, Deserialize, PartialEq, Debug)]
-    struct RequestVoteReply {
-        term: u64,
-        vote_granted: bool,
-    }
-
-    fn register_callback(r: &Arc<Mutex<RR>>,  mut req_receiver : Vec<Receiver<Vec<u8>>>) {
+    fn make_service(r: &Arc<Mutex<RR>>) -> Service {
+        let mut svc = Service::new("Raft");
         let rr = r.clone();
-        let req_receiver0 = req_receiver.remove(0);
-        thread::spawn(move || {     //RequestVote
-            loop {
-                let args = req_receiver0.recv().unwrap();
-
-                let reply = RequestVote(&rr, args);
-                let r1 = rr.lock().unwrap();
-                r1.reply_sender[0].send(reply).unwrap();
-            }
-        });
-        let rr = r.clone();
-        let req_receiver1 = req_receiver.remove(0);
-        thread::spawn(move || { //AppendEntries
-            loop {
-                let args = req_receiver1.recv().unwrap();
-
-                let reply = AppendEntries(&rr, args);
-                let r1 = rr.lock().unwrap();
-                r1.reply_sender[1].send(reply).unwrap();
-            }
-        });
+        svc.add_method("RequestVote", move |args: RequestVoteArgs| request_vote(&rr, args));
+        svc.add_method("AppendEntries", append_entries);
+        svc
     }
 
-    fn RequestVote(r: &Arc<Mutex<RR>>, args : Vec<u8>) -> (Vec<u8>, bool) {
-        //args *RequestVoteArgs, reply *RequestVoteReply
-
-        let rr = r.clone();
-        let r1 = rr.lock().unwrap();
-        let req : RequestVoteArgs = deserialize(&args[..]).unwrap();
-        println!("[RPC] call RequestVote, r.a: {}, args: {:?}", r1.a, args);
-        (Vec::new(), true)
-
-
-            // let reply : RequestVoteReply;
-            // let reply = serialize(&reply).unwrap();
-            // (reply, true)
+    fn request_vote(r: &Arc<Mutex<RR>>, req : RequestVoteArgs) -> bool {
+        let r1 = r.lock().unwrap();
+        println!("[RPC] call RequestVote, r.a: {}, args: {:?}", r1.a, req);
+        true
     }
 
-    fn AppendEntries(r: &Arc<Mutex<RR>>, args : Vec<u8>) -> (Vec<u8>, bool) {
-        //args *RequestVoteArgs, reply *RequestVoteReply
-        // let req : AppendEntriesArgs = deserialize(&args[..]).unwrap();
+    // echo the args back
+    fn append_entries(args : Vec<u8>) -> Vec<u8> {
         println!("call AppendEntries, args:{:?}", args);
-        (Vec::new(), true)
-
-
-            // let reply : RequestVoteReply;
-            // let reply = serialize(&reply).unwrap();
-            // (reply, true)
+        args
     }
 
     fn create_server(addr : String) -> Arc<Mutex<RR>> {
-        let mut req_sendv = Vec::new();
-        let mut reply_sendv = Vec::new();
-        let mut req_recvv = Vec::new();
-        let mut reply_recvv = Vec::new();
-
-        for i in (0..CALLBACK_NUMS) {
-            let (req_send, req_recv) = sync_channel(1);
-            let (reply_send, reply_recv) = sync_channel(1);
-
-            req_sendv.push(req_send);
-            reply_sendv.push(reply_send);
-            req_recvv.push(req_recv);
-            reply_recvv.push(reply_recv);
-        }
-
-        let rn1 = make_network(addr, req_sendv, reply_recvv);
+        let rn1 = make_network(addr);
 
         let r = RR {
             a : 10,
             peers : Vec::new(),
             network : rn1,
-            reply_sender : reply_sendv,
-            // req_receiver : req_recvv,
         };
         let ar = Arc::new(Mutex::new(r));
-        register_callback(&ar, req_recvv);
+        let svc = make_service(&ar);
+        ar.lock().unwrap().network.add_service(svc);
 
         ar
     }
 
-    fn create_servers(server_num : u32) -> Vec<Arc<Mutex<RR>>> {
-        let mut base_port = 7810;
+    fn create_servers(base_port : u32, server_num : u32) -> Vec<Arc<Mutex<RR>>> {
         let mut addrs = Vec::new();
-        for i in (0..server_num) {
-            addrs.push(format!("127.0.0.1:{}", base_port));
-            base_port += 1;
+        for i in 0..server_num {
+            addrs.push(format!("127.0.0.1:{}", base_port + i));
         }
 
         let mut servers = Vec::new();
@@ -376,26 +754,26 @@ mod tests {
             servers.push(create_server(addr.clone()));
         }
 
-        for i in (0..addrs.len()) {
-            let r1 = servers[i].clone();
-            let mut raft = r1.lock().unwrap();
+        for (i, server) in servers.iter().enumerate() {
+            let mut raft = server.lock().unwrap();
             let mut clients = Vec::new();
-            for j in (0..addrs.len()) {
+            for (j, addr) in addrs.iter().enumerate() {
                 if i == j {
                     clients.push(Client::new());
                 } else {
-                    let client = make_end(&raft.network, format!("client{}to{}", i, j), addrs[j].clone());
+                    let client = make_end(&raft.network, end_name(i as i32, j as i32), addr.clone());
                     clients.push(client);
                 }
             }
             raft.peers = clients;
         }
+        thread::sleep(Duration::from_millis(200));     // let the listeners come up
         servers
     }
 
     #[test]
     fn rpc_test() {
-        let servers = create_servers(3);
+        let servers = create_servers(7810, 3);
         println!("[RPC]:call create servers ok");
 
         let req =  RequestVoteArgs {
@@ -408,8 +786,307 @@ mod tests {
 
         let araft = servers[0].clone();
         let raft0 = araft.lock().unwrap();
-        raft0.peers[1].call(String::from("Raft.RequestVote"), req);
+        let (reply, ok) = raft0.peers[1].call(String::from("Raft.RequestVote"), req);
+        assert!(ok);
+        assert!(deserialize::<bool>(&reply).unwrap());
 
         println!("[RPC] test ok");
     }
+
+    #[test]
+    fn unknown_method_test() {
+        let rn = make_network(String::from("127.0.0.1:7830"));
+        let mut svc = Service::new("Raft");
+        svc.add_method("AppendEntries", append_entries);
+        rn.add_service(svc);
+
+        let req = |svc_meth: &str, args: Vec<u8>| ReqMsg {
+            id : 1,
+            end_name : String::from(""),
+            svc_meth : svc_meth.to_string(),
+            args_type : String::from("bin"),
+            args,
+        };
+        let args = serialize(&vec![1u8]).unwrap();
+        let reply = dispatch(&rn, req("Raft.AppendEntries", args.clone()));
+        assert!(reply.ok);
+        assert_eq!(reply.reply, args);
+
+        let reply = dispatch(&rn, req("Raft.Foo", args.clone()));
+        assert!(!reply.ok);
+        assert_eq!(reply.err, Some(ServerError::UnknownMethod(String::from("Raft.Foo"))));
+        let reply = dispatch(&rn, req("Foo.AppendEntries", args));
+        assert_eq!(reply.err, Some(ServerError::UnknownService(String::from("Foo"))));
+        let reply = dispatch(&rn, req("Raft.AppendEntries", vec![1]));
+        assert_eq!(reply.err, Some(ServerError::BadArgs));
+    }
+
+    #[test]
+    fn pool_test() {
+        let servers = create_servers(7820, 2);
+        let client = servers[0].lock().unwrap().peers[1].clone();
+
+        let echo = |client: &Client, args: Vec<u8>| {
+            let (reply, ok) = client.call(String::from("Raft.AppendEntries"), serialize(&args).unwrap());
+            assert!(ok);
+            deserialize::<Vec<u8>>(&reply).unwrap()
+        };
+
+        // concurrent calls share one connection and each gets its own reply
+        assert_eq!(echo(&client, vec![0]), vec![0]);
+        let conn = Connection::get(&client.server_addr, Duration::from_secs(1)).unwrap();
+        let mut handles = Vec::new();
+        for i in 0..8u8 {
+            let client = client.clone();
+            handles.push(thread::spawn(move || {
+                for j in 0..10u8 {
+                    assert_eq!(echo(&client, vec![i, j]), vec![i, j]);
+                }
+            }));
+        }
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert!(Arc::ptr_eq(&conn, &Connection::get(&client.server_addr, Duration::from_secs(1)).unwrap()));
+
+        // a second caller which dialed at the same time hangs up and shares the first one's
+        let late = Connection::dial(&client.server_addr, Duration::from_secs(1)).unwrap();
+        assert!(Arc::ptr_eq(&Connection::share(late.clone()), &conn));
+        assert!(late.broken.load(Ordering::SeqCst));
+
+        // a broken connection is replaced on the next call
+        conn.close();
+        assert!(!pool().lock().unwrap().contains_key(&client.server_addr));
+        assert_eq!(echo(&client, vec![1]), vec![1]);
+    }
+
+    #[test]
+    fn concurrent_test() {
+        let rn = make_network(String::from("127.0.0.1:7840"));
+        let mut svc = Service::new("Test");
+        svc.add_method("Sleep", |ms: u64| {
+            thread::sleep(Duration::from_millis(ms));
+            ms
+        });
+        rn.add_service(svc);
+        thread::sleep(Duration::from_millis(200));
+        let client = make_end(&rn, String::from("client"), String::from("127.0.0.1:7840"));
+
+        // ten slow calls of one method at once take about as long as one
+        let start = Instant::now();
+        let mut handles = Vec::new();
+        for i in 0..10u64 {
+            let client = client.clone();
+            handles.push(thread::spawn(move || {
+                let (reply, ok) = client.call(String::from("Test.Sleep"), serialize(&(500 + i)).unwrap());
+                assert!(ok);
+                assert_eq!(deserialize::<u64>(&reply).unwrap(), 500 + i);
+            }));
+        }
+        // and a fast one does not wait behind them
+        thread::sleep(Duration::from_millis(50));
+        let (reply, ok) = client.call(String::from("Test.Sleep"), serialize(&0u64).unwrap());
+        assert!(ok);
+        assert_eq!(deserialize::<u64>(&reply).unwrap(), 0);
+        assert!(start.elapsed() < Duration::from_millis(400));
+
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_millis(2000));
+    }
+
+    #[test]
+    fn service_pool_test() {
+        let rn = make_network(String::from("mem:rpc-service-pool-test"));
+        let mut slow = Service::new("Slow");
+        slow.add_method("Sleep", |ms: u64| {
+            thread::sleep(Duration::from_millis(ms));
+            ms
+        });
+        rn.add_service(slow);
+        let mut fast = Service::new("Fast");
+        fast.add_method("Echo", |n: u64| n);
+        rn.add_service(fast);
+        let client = make_end(&rn, String::from("client"), String::from("mem:rpc-service-pool-test"));
+
+        // every worker of Slow is blocked, calls to Fast still go through at once
+        let mut handles = Vec::new();
+        for _ in 0..MAX_WORKERS + 10 {
+            let client = client.clone();
+            handles.push(thread::spawn(move || {
+                client.call_with_timeout(String::from("Slow.Sleep"), serialize(&1000u64).unwrap(), Duration::from_secs(5))
+            }));
+        }
+        thread::sleep(Duration::from_millis(100));
+        let start = Instant::now();
+        let reply = client.call_with_timeout(String::from("Fast.Echo"), serialize(&7u64).unwrap(), Duration::from_millis(500));
+        assert_eq!(reply, Ok(serialize(&7u64).unwrap()));
+        assert!(start.elapsed() < Duration::from_millis(200));
+        for h in handles {
+            assert!(h.join().unwrap().is_ok());
+        }
+    }
+
+    #[test]
+    fn call_error_test() {
+        let rn = make_network(String::from("127.0.0.1:7850"));
+        let mut svc = Service::new("Test");
+        svc.add_method("Sleep", |ms: u64| {
+            thread::sleep(Duration::from_millis(ms));
+            ms
+        });
+        rn.add_service(svc);
+        thread::sleep(Duration::from_millis(200));
+        let client = make_end(&rn, String::from("client"), String::from("127.0.0.1:7850"));
+        let timeout = Duration::from_millis(200);
+        let call = |svc_meth: &str, args: Vec<u8>| client.call_with_timeout(svc_meth.to_string(), args, timeout);
+
+        assert_eq!(call("Test.Sleep", serialize(&10u64).unwrap()), Ok(serialize(&10u64).unwrap()));
+        // a server which accepted the call but never answers
+        let start = Instant::now();
+        assert_eq!(call("Test.Sleep", serialize(&2000u64).unwrap()), Err(RpcError::Timeout));
+        assert!(start.elapsed() < Duration::from_millis(1000));
+        assert_eq!(call("Test.Foo", Vec::new()), Err(RpcError::UnknownMethod(String::from("Test.Foo"))));
+        assert_eq!(call("Foo.Sleep", Vec::new()), Err(RpcError::UnknownMethod(String::from("Foo"))));
+        assert!(matches!(call("Test.Sleep", vec![1]), Err(RpcError::RemoteError(_))));
+
+        let nobody = Client { end_name: String::from(""), server_addr: String::from("127.0.0.1:7851") };
+        assert_eq!(nobody.call_with_timeout(String::from("Test.Sleep"), Vec::new(), timeout), Err(RpcError::ConnectFailed));
+    }
+
+    #[test]
+    fn write_deadline_test() {
+        // a server which accepts connections but never reads from them
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let mut streams = Vec::new();
+            for stream in listener.incoming() {
+                streams.push(stream);
+            }
+        });
+        let client = Client { end_name: String::from(""), server_addr: addr };
+        let timeout = Duration::from_millis(300);
+
+        // a request far bigger than the socket buffers, and a call stuck behind it
+        let conn = Connection::get(&client.server_addr, timeout).unwrap();
+        let big = (vec![0; 32 << 20], Instant::now() + Duration::from_millis(1000));
+        conn.outgoing.lock().unwrap().as_ref().unwrap().send(big).unwrap();
+        let start = Instant::now();
+        assert_eq!(client.call_with_timeout(String::from("Test.Echo"), Vec::new(), timeout), Err(RpcError::Timeout));
+        assert!(start.elapsed() < timeout + Duration::from_millis(100));
+
+        // the big one did not make it by its deadline, which breaks the connection
+        thread::sleep(Duration::from_millis(1000));
+        assert!(conn.broken.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn reply_deadline_test() {
+        let rn = make_network(String::from("127.0.0.1:7870"));
+        let mut svc = Service::new("Test");
+        svc.add_method("Big", |n: usize| "x".repeat(n));
+        rn.add_service(svc);
+        thread::sleep(Duration::from_millis(200));
+
+        // a client which asks for a reply far bigger than the socket buffers
+        // and never reads it
+        let mut stream = TcpStream::connect("127.0.0.1:7870").unwrap();
+        let req = ReqMsg {
+            id : 1,
+            end_name : String::from(""),
+            svc_meth : String::from("Test.Big"),
+            args_type : String::from("bin"),
+            args : serialize(&(32usize << 20)).unwrap(),
+        };
+        let deadline = Instant::now() + Duration::from_secs(1);
+        write_frame_until(&mut stream, &serialize(&req).unwrap(), deadline).unwrap();
+
+        // loses its connection once the reply is overdue, rather than holding a worker forever
+        thread::sleep(Duration::from_millis(REPLY_TIMEOUT + 8000));  // making the reply is slow in debug builds too
+        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut got = Vec::new();
+        if let Err(err) = stream.read_to_end(&mut got) {
+            assert!(!matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut), "{:?}", err);
+        }
+        assert!(got.len() < 32 << 20);
+    }
+
+    #[test]
+    fn fault_test() {
+        let rn = make_network(String::from("127.0.0.1:7860"));
+        let mut svc = Service::new("Test");
+        svc.add_method("Echo", |n: u64| n);
+        rn.add_service(svc);
+        thread::sleep(Duration::from_millis(200));
+        let client = make_end(&rn, String::from("client"), String::from("127.0.0.1:7860"));
+        let timeout = Duration::from_millis(300);
+        let call = || client.call_with_timeout(String::from("Test.Echo"), serialize(&1u64).unwrap(), timeout);
+        assert!(call().is_ok());
+
+        // a disabled end fails fast, or hangs until its deadline with long delays
+        rn.enable("client", false);
+        let start = Instant::now();
+        assert_eq!(call(), Err(RpcError::ConnectFailed));
+        assert!(start.elapsed() < timeout);
+        rn.set_long_delays(true);
+        assert_eq!(call(), Err(RpcError::Timeout));
+        rn.enable("client", true);
+        assert!(call().is_ok());
+        assert_eq!(rn.count(), 2);
+
+        // everything is lost on the way in or out
+        rn.set_reliable(false);
+        rn.set_drop_rate(1.0);
+        assert_eq!(call(), Err(RpcError::ConnectFailed));
+        rn.set_drop_rate(0.0);
+        assert!(call().is_ok());
+    }
+
+    #[test]
+    fn mem_transport_test() {
+        let rn = make_network(String::from("mem:rpc-mem-test"));
+        let mut svc = Service::new("Test");
+        svc.add_method("Echo", |n: u64| n);
+        rn.add_service(svc);
+        let client = make_end(&rn, String::from("client"), String::from("mem:rpc-mem-test"));
+        let timeout = Duration::from_millis(200);
+        let call = |client: &Client| client.call_with_timeout(String::from("Test.Echo"), serialize(&7u64).unwrap(), timeout);
+        assert_eq!(call(&client), Ok(serialize(&7u64).unwrap()));
+        assert_eq!(rn.count(), 1);
+
+        let nobody = Client { end_name: String::from(""), server_addr: String::from("mem:rpc-mem-test-nobody") };
+        assert_eq!(call(&nobody), Err(RpcError::ConnectFailed));
+
+        // faults work without sockets too
+        rn.enable("client", false);
+        rn.set_long_delays(true);
+        let start = Instant::now();
+        assert_eq!(call(&client), Err(RpcError::Timeout));
+        assert!(start.elapsed() >= timeout);
+    }
+
+    #[test]
+    fn frame_test() {
+        // a message much bigger than a single tcp read comes back whole
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let msg = read_frame(&mut stream).unwrap();
+            write_frame_until(&mut stream, &msg, deadline).unwrap();
+        });
+        let msg: Vec<u8> = (0..1 << 20).map(|i| i as u8).collect();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write_frame_until(&mut stream, &msg, deadline).unwrap();
+        assert_eq!(read_frame(&mut stream).unwrap(), msg);
+
+        // a corrupt length is rejected instead of allocating it
+        let mut bad: &[u8] = &[0xff, 0xff, 0xff, 0xff, 0];
+        assert!(read_frame(&mut bad).is_err());
+        let mut short: &[u8] = &[0, 0, 0, 8, 1, 2];
+        assert!(read_frame(&mut short).is_err());
+    }
 }
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+
+use bincode::serialize;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+
+use super::config::Configuration;
+use super::node::{Message, Node};
+
+// a cluster of nodes on virtual time. every tick each live node ticks once,
+// in random order, then the messages due by now are delivered in random order.
+// a message is lost with drop_rate and otherwise takes 1 to max_delay ticks.
+// the seed alone decides the whole run, so a failing seed replays exactly.
+pub struct Sim {
+    pub nodes: Vec<Node>,
+    pub drop_rate: f64,
+    pub max_delay: u64,
+    pub snapshot_every: Option<usize>,  // compact a node's log once it committed this many entries past its snapshot
+    seed: u64,
+    rng: StdRng,
+    now: u64,
+    config: Configuration,
+    disk: Vec<(Vec<u8>, Vec<u8>)>,  // hard state and snapshot each node persisted
+    up: Vec<bool>,
+    group: Vec<usize>,              // partition of each node, messages only flow within one
+    in_flight: Vec<(u64, Message)>, // tick the message arrives at
+    snapshot_index: Vec<usize>,     // last index each node compacted its log to
+    applied: Vec<usize>,            // last index each node applied
+    committed: HashMap<usize, (u64, Vec<u8>)>,  // term and command applied at each index
+    leaders: HashMap<u64, i32>,     // who led each term
+}
+
+impl Sim {
+    pub fn new(n: usize, seed: u64) -> Sim {
+        let addrs: Vec<String> = (0..n).map(|i| format!("sim-{}", i)).collect();
+        let config = Configuration::from_addrs(&addrs);
+        let mut rng = StdRng::seed_from_u64(seed);
+        let nodes = (0..n).map(|i| Node::new(i as i32, config.clone(), &[], Vec::new(), rng.gen())).collect();
+        Sim {
+            nodes,
+            drop_rate: 0.0,
+            max_delay: 3,
+            snapshot_every: None,
+            seed,
+            rng,
+            now: 0,
+            config,
+            disk: vec![(Vec::new(), Vec::new()); n],
+            up: vec![true; n],
+            group: vec![0; n],
+            in_flight: Vec::new(),
+            snapshot_index: vec![0; n],
+            applied: vec![0; n],
+            committed: HashMap::new(),
+            leaders: HashMap::new(),
+        }
+    }
+
+    pub fn run(&mut self, ticks: u64) {
+        for _ in 0..ticks {
+            self.tick();
+        }
+    }
+
+    // advance virtual time by one tick.
+    pub fn tick(&mut self) {
+        self.now += 1;
+        let mut order: Vec<usize> = (0..self.nodes.len()).filter(|&i| self.up[i]).collect();
+        order.shuffle(&mut self.rng);
+        for i in order {
+            self.nodes[i].tick();
+            self.process(i);
+        }
+
+        let now = self.now;
+        let (mut due, rest): (Vec<_>, Vec<_>) = self.in_flight.drain(..).partition(|(at, _)| *at <= now);
+        self.in_flight = rest;
+        due.shuffle(&mut self.rng);
+        for (_, msg) in due {
+            let (from, to) = (msg.from as usize, msg.to as usize);
+            if to >= self.nodes.len() || !self.up[to] || self.group[from] != self.group[to] {
+                continue;
+            }
+            self.nodes[to].step(msg);
+            self.process(to);
+        }
+        self.check_leaders();
+    }
+
+    // the live leader of the highest term, if any.
+    pub fn leader(&self) -> Option<usize> {
+        (0..self.nodes.len())
+            .filter(|&i| self.up[i] && self.nodes[i].is_leader())
+            .max_by_key(|&i| self.nodes[i].term())
+    }
+
+    // propose command at the leader, false if there is none or it refused.
+    pub fn propose(&mut self, command: &[u8]) -> bool {
+        match self.leader() {
+            Some(i) => {
+                let (_, _, ok) = self.nodes[i].propose(command);
+                self.process(i);
+                ok
+            },
+            None => false,
+        }
+    }
+
+    // servers only reach those in the same group, a server missing from every group is cut off.
+    pub fn partition(&mut self, groups: &[Vec<usize>]) {
+        for i in 0..self.nodes.len() {
+            self.group[i] = groups.iter().position(|g| g.contains(&i)).map_or(groups.len() + i, |g| g);
+        }
+    }
+
+    pub fn heal(&mut self) {
+        self.group = vec![0; self.nodes.len()];
+    }
+
+    // stop node i, it loses everything it did not persist.
+    pub fn crash(&mut self, i: usize) {
+        self.up[i] = false;
+    }
+
+    // start node i again from what it persisted.
+    pub fn restart(&mut self, i: usize) {
+        let (state, snapshot) = self.disk[i].clone();
+        self.nodes[i] = Node::new(i as i32, self.config.clone(), &state, snapshot, self.rng.gen());
+        self.applied[i] = self.nodes[i].commit_index();
+        self.snapshot_index[i] = self.applied[i];
+        self.up[i] = true;
+    }
+
+    pub fn is_up(&self, i: usize) -> bool {
+        self.up[i]
+    }
+
+    // highest index every node applied.
+    pub fn min_applied(&self) -> usize {
+        self.applied.iter().cloned().min().unwrap_or(0)
+    }
+
+    // carry out what node i asked for and check what it applied.
+    fn process(&mut self, i: usize) {
+        let ready = self.nodes[i].ready();
+        if let Some(state) = ready.state {
+            self.disk[i].0 = state;
+        }
+        if let Some(snapshot) = ready.snapshot {
+            self.disk[i].1 = snapshot;
+        }
+        for msg in ready.messages {
+            if self.rng.gen::<f64>() < self.drop_rate {
+                continue;
+            }
+            let at = self.now + self.rng.gen_range(1, self.max_delay + 1);
+            self.in_flight.push((at, msg));
+        }
+        for msg in ready.apply {
+            if !msg.snapshot.is_empty() {
+                self.applied[i] = msg.index;
+                continue;
+            }
+            assert_eq!(msg.index, self.applied[i] + 1, "seed {}: server {} applied out of order", self.seed, i);
+            self.applied[i] = msg.index;
+            let entry = (msg.term, msg.command);
+            match self.committed.get(&msg.index) {
+                Some(prev) => assert_eq!(prev, &entry, "seed {}: server {} applied a different entry at {}", self.seed, i, msg.index),
+                None => { self.committed.insert(msg.index, entry); },
+            }
+        }
+        if let Some(every) = self.snapshot_every {
+            let index = self.applied[i];
+            if index >= self.snapshot_index[i] + every {
+                self.nodes[i].compact(index, serialize(&index).unwrap());
+                self.snapshot_index[i] = index;
+                self.process(i);
+            }
+        }
+    }
+
+    // at most one leader per term.
+    fn check_leaders(&mut self) {
+        for (i, node) in self.nodes.iter().enumerate() {
+            if self.up[i] && node.is_leader() {
+                let leader = *self.leaders.entry(node.term()).or_insert(i as i32);
+                assert_eq!(leader, i as i32, "seed {}: two leaders in term {}", self.seed, node.term());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::node::MAX_ELECTION_TICKS;
+    use super::super::ReadError;
+
+    // term, leadership, commit index and last index of every node.
+    fn fingerprint(sim: &Sim) -> Vec<(u64, bool, usize, usize)> {
+        sim.nodes.iter().map(|n| (n.term(), n.is_leader(), n.commit_index(), n.last_index())).collect()
+    }
+
+    fn chaos(seed: u64) -> Sim {
+        let mut sim = Sim::new(5, seed);
+        sim.drop_rate = 0.1;
+        sim.max_delay = 5;
+        sim.snapshot_every = Some(20);
+        let mut rng = StdRng::seed_from_u64(seed);
+        for t in 0..3000u32 {
+            if t % 10 == 0 {
+                sim.propose(&t.to_be_bytes());
+            }
+            if t % 300 == 150 {
+                match rng.gen_range(0, 3) {
+                    0 => {
+                        let i = rng.gen_range(0, 5);
+                        if sim.is_up(i) { sim.crash(i) } else { sim.restart(i) }
+                    },
+                    1 => {
+                        let mut ids: Vec<usize> = (0..5).collect();
+                        ids.shuffle(&mut rng);
+                        let (a, b) = ids.split_at(rng.gen_range(1, 5));
+                        sim.partition(&[a.to_vec(), b.to_vec()]);
+                    },
+                    _ => sim.heal(),
+                }
+            }
+            sim.tick();
+        }
+        sim
+    }
+
+    #[test]
+    fn sim_election_test() {
+        for seed in 0..30 {
+            let mut sim = Sim::new(5, seed);
+            sim.drop_rate = 0.1;
+            sim.run(300);
+            assert!(sim.leader().is_some(), "seed {}: no leader", seed);
+        }
+    }
+
+    #[test]
+    fn sim_replay_test() {
+        let a = chaos(7);
+        let b = chaos(7);
+        assert_eq!(fingerprint(&a), fingerprint(&b));
+        assert_eq!(a.committed, b.committed);
+    }
+
+    #[test]
+    fn sim_chaos_test() {
+        for seed in 0..10 {
+            let mut sim = chaos(seed);
+            // once everything is back the cluster catches up on every entry
+            sim.heal();
+            for i in 0..5 {
+                if !sim.is_up(i) {
+                    sim.restart(i);
+                }
+            }
+            sim.run(500);
+            assert!(sim.propose(b"last"), "seed {}: no leader after healing", seed);
+            sim.run(200);
+            let last = sim.nodes[sim.leader().unwrap()].last_index();
+            assert_eq!(sim.min_applied(), last, "seed {}: servers did not catch up", seed);
+            assert_eq!(sim.committed[&last].1, b"last".to_vec());
+        }
+    }
+
+    #[test]
+    fn sim_check_quorum_test() {
+        let mut sim = Sim::new(3, 1);
+        sim.run(200);
+        let leader = sim.leader().unwrap();
+        let term = sim.nodes[leader].term();
+
+        // a leader cut off from the others steps down, reads never confirm it
+        let others: Vec<usize> = (0..3).filter(|&i| i != leader).collect();
+        sim.partition(&[vec![leader], others]);
+        sim.run(20);
+        let read = sim.nodes[leader].read_index().unwrap();
+        sim.run(2 * MAX_ELECTION_TICKS);
+        assert_eq!(sim.nodes[leader].read_ready(read), Err(ReadError::NotLeader));
+        assert!(!sim.nodes[leader].is_leader());
+        // while the others elected a new one
+        let new_leader = sim.leader().unwrap();
+        assert_ne!(new_leader, leader);
+        assert!(sim.nodes[new_leader].term() > term);
+    }
+}